@@ -0,0 +1,116 @@
+use fnv::FnvHasher;
+use log::warn;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::path::{Component, Path, PathBuf};
+
+use crate::build_rules::CompilationUnit;
+use crate::model::Project;
+
+const CACHE_FILE_NAME: &str = ".hotstuff-cache";
+
+/// The keys and outputs of every compilation unit in the last build, kept in
+/// the output folder. Comparing modification times tells us when an input
+/// changed, but not when a unit itself changed: a document joining or leaving
+/// an index, or a site switching templates. Units whose key is not in the
+/// cache are always rebuilt, and outputs that are no longer planned for can
+/// be cleaned up.
+#[derive(Debug, Clone)]
+pub struct BuildCache {
+    path: PathBuf,
+    keys: HashSet<u64>,
+    outputs: HashSet<PathBuf>,
+}
+
+pub fn key(cunit: &CompilationUnit) -> u64 {
+    let cunit = match cunit {
+        CompilationUnit::CacheHit { unit } => unit,
+        unit => unit,
+    };
+    // Keys outlive the binary that wrote them, so they come from a hasher
+    // whose algorithm is fixed instead of the one std happens to use.
+    let mut hasher = FnvHasher::default();
+    cunit.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl BuildCache {
+    pub fn name() -> String {
+        CACHE_FILE_NAME.to_string()
+    }
+
+    fn output_dir(&self) -> &Path {
+        self.path.parent().unwrap_or_else(|| Path::new("."))
+    }
+
+    pub fn from_project(project: Project) -> BuildCache {
+        let output_dir = project.output_dir();
+        let path = output_dir.join(BuildCache::name());
+        let mut keys = HashSet::new();
+        let mut outputs = HashSet::new();
+
+        for line in std::fs::read_to_string(&path).unwrap_or_default().lines() {
+            let (key, output) = line.split_once(' ').unwrap_or((line, ""));
+            if let Ok(key) = u64::from_str_radix(key, 16) {
+                keys.insert(key);
+            }
+            // Outputs are kept relative to the output folder, and we never
+            // trust one that would take us out of it.
+            let output = Path::new(output);
+            if !output.as_os_str().is_empty()
+                && output
+                    .components()
+                    .all(|part| matches!(part, Component::Normal(_)))
+            {
+                outputs.insert(output_dir.join(output));
+            }
+        }
+
+        BuildCache {
+            path,
+            keys,
+            outputs,
+        }
+    }
+
+    pub fn contains(&self, cunit: &CompilationUnit) -> bool {
+        self.keys.contains(&key(cunit))
+    }
+
+    /// The outputs of the last build.
+    pub fn outputs(&self) -> impl Iterator<Item = &PathBuf> {
+        self.outputs.iter()
+    }
+
+    /// Replaces the contents of the cache with the units that were just
+    /// built, writing them out only if they changed.
+    pub fn save<'a, I>(self, cunits: I)
+    where
+        I: Iterator<Item = &'a CompilationUnit>,
+    {
+        let mut keys = HashSet::new();
+        let mut lines = vec![];
+        for cunit in cunits {
+            let output = match cunit.output() {
+                Some(output) => output,
+                None => continue,
+            };
+            let output = output
+                .strip_prefix(self.output_dir())
+                .map(Path::to_path_buf)
+                .unwrap_or_default();
+            let line = format!("{:016x} {}", key(cunit), output.to_string_lossy());
+            if keys.insert(key(cunit)) {
+                lines.push(line);
+            }
+        }
+
+        if keys == self.keys || !self.output_dir().exists() {
+            return;
+        }
+        lines.sort();
+        if let Err(err) = std::fs::write(&self.path, lines.join("\n")) {
+            warn!("Could not write build cache to {:?}: {}", self.path, err);
+        }
+    }
+}
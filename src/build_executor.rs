@@ -1,9 +1,14 @@
-use log::{debug, info};
+use log::{debug, error, info, warn};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 
-use crate::build_graph::BuildPlan;
-use crate::build_rules::{compile_unit, Artifact, CompilationUnit};
+use crate::build_cache::BuildCache;
+use crate::build_graph::{is_same_path, BuildPlan};
+use crate::build_report::{BuildReport, Status, UnitReport};
+use crate::build_rules::{compile_unit, CompilationUnit};
+use crate::trace::Trace;
 
-fn mstat(path: std::path::PathBuf) -> std::time::SystemTime {
+fn mstat(path: &Path) -> std::time::SystemTime {
     if let Ok(meta) = std::fs::metadata(path) {
         meta.modified().unwrap()
     } else {
@@ -11,93 +16,407 @@ fn mstat(path: std::path::PathBuf) -> std::time::SystemTime {
     }
 }
 
-impl BuildPlan {
-    pub fn compute_diff(self) -> BuildPlan {
-        self.map(|cunit| match cunit {
-            CompilationUnit::CreateDir { path } => {
-                let unit = CompilationUnit::CreateDir { path: path.clone() };
-                if std::fs::canonicalize(path).is_ok() {
-                    CompilationUnit::CacheHit {
-                        unit: Box::new(unit),
-                    }
-                } else {
-                    unit
-                }
+/// Why a unit has to run again.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reason {
+    NotBuilt,
+    MissingOutput(PathBuf),
+    ChangedInput { input: PathBuf, output: PathBuf },
+    LeftOver(PathBuf),
+    Dependency(Box<CompilationUnit>),
+}
+
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reason::NotBuilt => write!(
+                f,
+                "it was not part of the last build, or it changed since then"
+            ),
+            Reason::MissingOutput(output) => write!(f, "{:?} does not exist", output),
+            Reason::ChangedInput { input, output } => {
+                write!(f, "{:?} changed since {:?} was written", input, output)
             }
+            Reason::LeftOver(path) => write!(f, "{:?} is left over from an earlier build", path),
+            Reason::Dependency(unit) => write!(
+                f,
+                "it depends on the {} of {:?}, which runs again",
+                unit.rule(),
+                unit.path()
+            ),
+        }
+    }
+}
 
-            CompilationUnit::Copy { input, output } => {
-                let unit = CompilationUnit::Copy {
-                    input: input.clone(),
-                    output: output.clone(),
-                };
-                if mstat(input) >= mstat(output) {
-                    unit
-                } else {
-                    CompilationUnit::CacheHit {
-                        unit: Box::new(unit),
-                    }
-                }
+/// Why a unit has to run again, on its own. Units that ran in the last build
+/// and read from nothing that changed since have no reason to.
+fn reasons(cunit: &CompilationUnit, cache: &BuildCache) -> Vec<Reason> {
+    // Some inputs are written in the same instant as their output, so they
+    // only count as changed when they are strictly newer.
+    let changed = |inputs: &[PathBuf], output: &Path, strictly: bool| -> Vec<Reason> {
+        if !output.exists() {
+            return vec![Reason::MissingOutput(output.to_path_buf())];
+        }
+        let mstat_output = mstat(output);
+        inputs
+            .iter()
+            .filter(|input| {
+                let mstat_input = mstat(input);
+                mstat_input > mstat_output || (!strictly && mstat_input == mstat_output)
+            })
+            .map(|input| Reason::ChangedInput {
+                input: input.clone(),
+                output: output.to_path_buf(),
+            })
+            .collect()
+    };
+    let mut reasons = match cunit {
+        CompilationUnit::CreateDir { .. }
+        | CompilationUnit::Delete { .. }
+        | CompilationUnit::CacheHit { .. } => vec![],
+        cunit if cache.contains(cunit) => vec![],
+        _ => vec![Reason::NotBuilt],
+    };
+
+    match cunit {
+        CompilationUnit::CreateDir { path } => {
+            if std::fs::canonicalize(path).is_err() {
+                reasons.push(Reason::MissingOutput(path.clone()));
             }
+        }
 
-            CompilationUnit::Compile { input, output } => {
-                let unit = CompilationUnit::Compile {
-                    input: input.clone(),
-                    output: output.clone(),
-                };
-                if mstat(input) >= mstat(output) {
-                    unit
-                } else {
-                    CompilationUnit::CacheHit {
-                        unit: Box::new(unit),
-                    }
-                }
+        CompilationUnit::Compile {
+            input,
+            output,
+            dependencies,
+            ..
+        } => {
+            reasons.extend(changed(std::slice::from_ref(input), output, false));
+            reasons.extend(changed(dependencies, output, true));
+        }
+
+        CompilationUnit::Template { output, .. } => {
+            reasons.extend(changed(&cunit.inputs(), output, true));
+        }
+
+        CompilationUnit::Copy { output, .. }
+        | CompilationUnit::Generate { output, .. }
+        | CompilationUnit::Feed { output, .. }
+        | CompilationUnit::Json { output, .. }
+        | CompilationUnit::SearchIndex { output, .. } => {
+            reasons.extend(changed(&cunit.inputs(), output, false));
+        }
+
+        // These only depend on what the plan knows, so they are rebuilt
+        // when their key changes or their output is gone.
+        CompilationUnit::Sitemap { output, .. }
+        | CompilationUnit::Robots { output, .. }
+        | CompilationUnit::SearchScript { output, .. } => {
+            if !output.exists() {
+                reasons.push(Reason::MissingOutput(output.clone()));
             }
+        }
 
-            CompilationUnit::Template {
-                input,
-                output,
-                template,
-            } => {
-                let unit = CompilationUnit::Template {
-                    input: input.clone(),
-                    output: output.clone(),
-                    template: template.clone(),
-                };
-                let mstat_output = mstat(output);
-                if mstat(input) > mstat_output || mstat(template) > mstat_output {
-                    unit
-                } else {
-                    CompilationUnit::CacheHit {
-                        unit: Box::new(unit),
-                    }
-                }
+        CompilationUnit::Delete { path } => {
+            if path.exists() {
+                reasons.push(Reason::LeftOver(path.clone()));
             }
+        }
 
-            hit @ CompilationUnit::CacheHit { .. } => hit,
-        })
+        CompilationUnit::CacheHit { unit } => reasons.extend(self::reasons(unit, cache)),
     }
+    reasons.dedup();
+    reasons
+}
+
+/// A unit, and why it runs again, if it does.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Explanation {
+    pub unit: CompilationUnit,
+    pub reasons: Vec<Reason>,
+}
 
-    pub fn execute(self) -> Vec<Artifact> {
+impl BuildPlan {
+    /// Outputs of the last build that are not planned for anymore.
+    fn stale_outputs(&self, cache: &BuildCache) -> Vec<BuildPlan> {
+        let planned: HashSet<PathBuf> = self
+            .units()
+            .filter_map(|cunit| match cunit {
+                CompilationUnit::Delete { path } => Some(path.clone()),
+                cunit => cunit.output(),
+            })
+            .collect();
+        let mut stale: Vec<PathBuf> = cache
+            .outputs()
+            .filter(|output| !planned.contains(*output))
+            .cloned()
+            .collect();
+        stale.sort();
+        stale
+            .into_iter()
+            .map(|path| BuildPlan::start_with(CompilationUnit::Delete { path }))
+            .collect()
+    }
+
+    /// Turns every unit that doesn't need to run again into a cache hit. A
+    /// unit runs again when its inputs changed since it last ran, or when
+    /// anything it depends on runs again, so changes reach everything built
+    /// from them.
+    pub fn compute_diff(self, cache: &BuildCache) -> BuildPlan {
+        let stale = self.stale_outputs(cache);
+        self.and_also(stale)
+            .diff(|cunit| reasons(cunit, cache).is_empty())
+    }
+
+    /// Why the units working on `path` run again, or not, along with the
+    /// units they depend on that run again.
+    pub fn explain(self, cache: &BuildCache, path: &Path) -> Vec<Explanation> {
+        let plan = self.compute_diff(cache);
+        let units: Vec<&CompilationUnit> = plan.units().collect();
+        units
+            .iter()
+            .enumerate()
+            .filter(|(_, cunit)| is_same_path(&cunit.path(), path))
+            .map(|(index, cunit)| {
+                let forced = plan.dependencies(index).filter_map(|dep| match units[dep] {
+                    CompilationUnit::CacheHit { .. } => None,
+                    dep => Some(Reason::Dependency(Box::new(dep.clone()))),
+                });
+                Explanation {
+                    unit: (*cunit).clone(),
+                    reasons: reasons(cunit, cache).into_iter().chain(forced).collect(),
+                }
+            })
+            .collect()
+    }
+
+    /// Runs every unit that is not a cache hit, in order. A unit that fails
+    /// doesn't stop the build, but nothing that depends on it is run.
+    pub fn execute(self, cache: BuildCache) -> BuildReport {
+        self.execute_traced(cache, &Trace::new())
+    }
+
+    /// Runs the build like `execute`, recording every unit that runs in a
+    /// trace.
+    pub fn execute_traced(self, cache: BuildCache, trace: &Trace) -> BuildReport {
         let t0 = std::time::Instant::now();
-        let mut artifacts = vec![];
-        for cunit in self.breadth_first_iter() {
-            match cunit {
-                hit @ CompilationUnit::CacheHit { .. } => debug!("\x1b[90m{:?}\x1b[0m", hit),
+        let mut reports: Vec<UnitReport> = vec![];
+        for (index, cunit) in self.units().enumerate() {
+            let t1 = std::time::Instant::now();
+            let blocked = self.dependencies(index).any(|dep| {
+                reports
+                    .get(dep)
+                    .is_some_and(|dep| matches!(dep.status, Status::Failed | Status::Blocked))
+            });
+            let (status, artifact, error) = match cunit {
+                hit @ CompilationUnit::CacheHit { .. } => {
+                    debug!("\x1b[90m{:?}\x1b[0m", hit);
+                    (Status::CacheHit, None, None)
+                }
+                unit if blocked => {
+                    warn!("Skipping {:?}, since something it depends on failed", unit);
+                    (Status::Blocked, None, None)
+                }
                 unit => {
                     info!("\x1b[94m{:?}\x1b[0m", unit.clone());
-                    let artifact = compile_unit(unit.clone())
-                        .unwrap_or_else(|_| panic!("Could not complete task: {:?}", unit));
-                    artifacts.push(artifact);
+                    let result = match compile_unit(unit.clone()) {
+                        Ok(artifact) => (Status::Built, Some(artifact), None),
+                        Err(err) => {
+                            error!("Could not complete task: {:?}: {}", unit, err);
+                            (Status::Failed, None, Some(err.to_string()))
+                        }
+                    };
+                    trace.add(
+                        &format!("{} {}", unit.rule(), unit.path().display()),
+                        "compile_unit",
+                        t1,
+                        serde_json::json!({
+                            "inputs": unit.inputs(),
+                            "status": result.0.name(),
+                        }),
+                    );
+                    result
                 }
-            }
+            };
+            // Units that failed or never ran may have left an old output
+            // behind, which says nothing about this build.
+            let size = Some(status)
+                .filter(|status| matches!(status, Status::Built | Status::CacheHit))
+                .and_then(|_| cunit.output())
+                .and_then(|output| std::fs::metadata(output).ok())
+                .filter(|meta| meta.is_file())
+                .map(|meta| meta.len());
+            reports.push(UnitReport {
+                unit: cunit.clone(),
+                status,
+                duration: t1.elapsed(),
+                size,
+                error,
+                artifact,
+            });
         }
-        if !artifacts.is_empty() {
+
+        // Units that didn't make it are left out, so they run next time.
+        cache.save(
+            reports
+                .iter()
+                .filter(|report| matches!(report.status, Status::Built | Status::CacheHit))
+                .map(|report| &report.unit),
+        );
+        let report = BuildReport::new(reports, t0.elapsed());
+        let built = report.count(Status::Built);
+        if built > 0 {
             info!(
                 "Built {} artifacts in {}ms",
-                artifacts.len(),
-                t0.elapsed().as_millis()
+                built,
+                report.duration().as_millis()
             );
         }
-        artifacts
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Project;
+    use std::time::{Duration, SystemTime};
+
+    fn rebuilt(plan: &BuildPlan) -> Vec<PathBuf> {
+        plan.units()
+            .filter_map(|cunit| match cunit {
+                CompilationUnit::CacheHit { .. } => None,
+                CompilationUnit::CreateDir { path } => Some(path.clone()),
+                cunit => cunit.output(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_compute_diff() {
+        let root = std::env::temp_dir().join(format!("hotstuff-diff-{}", std::process::id()));
+        let (src, out) = (root.join("src"), root.join("out"));
+        std::fs::create_dir_all(&src).unwrap();
+        // Sources are older than anything built from them, and then newer
+        // once they change, without waiting on the clock.
+        let touch = |path: &Path, modified: SystemTime| {
+            let file = std::fs::File::options().write(true).open(path).unwrap();
+            file.set_modified(modified).unwrap();
+        };
+        let an_hour = Duration::from_secs(60 * 60);
+        for file in &["template.html", "a.md", "b.txt"] {
+            std::fs::write(src.join(file), file).unwrap();
+            touch(&src.join(file), SystemTime::now() - an_hour);
+        }
+        let copy = |file: &str| {
+            BuildPlan::start_with(CompilationUnit::Copy {
+                input: src.join(file),
+                output: out.join(file),
+            })
+        };
+        let plan = BuildPlan::start_with(CompilationUnit::CreateDir { path: out.clone() })
+            .and_then(vec![
+                copy("template.html").and_then(vec![copy("a.md")]),
+                copy("b.txt"),
+            ])
+            .sorted()
+            .unwrap();
+        let project = Project::new().with_output_dir(out.clone());
+        let build = || {
+            let cache = BuildCache::from_project(project.clone());
+            let diff = plan.clone().compute_diff(&cache);
+            diff.clone().execute(cache);
+            rebuilt(&diff)
+        };
+        let everything = vec![
+            out.clone(),
+            out.join("template.html"),
+            out.join("a.md"),
+            out.join("b.txt"),
+        ];
+
+        assert_eq!(build(), everything);
+        assert_eq!(build(), Vec::<PathBuf>::new());
+
+        for file in &["template.html", "a.md", "b.txt"] {
+            touch(&out.join(file), SystemTime::now() - an_hour / 2);
+        }
+        std::fs::write(src.join("template.html"), "changed").unwrap();
+        let cache = BuildCache::from_project(project.clone());
+        let explain = |path: &str| -> Vec<Vec<Reason>> {
+            plan.clone()
+                .explain(&cache, &out.join(path))
+                .into_iter()
+                .map(|explanation| explanation.reasons)
+                .collect()
+        };
+        assert_eq!(
+            explain("template.html"),
+            vec![vec![Reason::ChangedInput {
+                input: src.join("template.html"),
+                output: out.join("template.html"),
+            }]]
+        );
+        assert_eq!(
+            explain("a.md"),
+            vec![vec![Reason::Dependency(Box::new(CompilationUnit::Copy {
+                input: src.join("template.html"),
+                output: out.join("template.html"),
+            }))]]
+        );
+        assert_eq!(explain("b.txt"), vec![vec![]]);
+        assert_eq!(build(), vec![out.join("template.html"), out.join("a.md")]);
+
+        std::fs::remove_file(out.join("b.txt")).unwrap();
+        assert_eq!(build(), vec![out.join("b.txt")]);
+
+        std::fs::remove_dir_all(&out).unwrap();
+        assert_eq!(build(), everything);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_execute_report() {
+        let root = std::env::temp_dir().join(format!("hotstuff-report-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("out")).unwrap();
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        // Left over from an earlier build.
+        std::fs::write(root.join("out/b.txt"), "old").unwrap();
+        std::fs::write(root.join("out/c.txt"), "old").unwrap();
+        let copy = |input: &str, output: &str| {
+            BuildPlan::start_with(CompilationUnit::Copy {
+                input: root.join(input),
+                output: root.join("out").join(output),
+            })
+        };
+        let plan = BuildPlan::start_with(CompilationUnit::CreateDir {
+            path: root.join("out"),
+        })
+        .and_then(vec![
+            copy("missing.txt", "b.txt").and_then(vec![copy("out/b.txt", "c.txt")]),
+            copy("a.txt", "a.txt"),
+        ])
+        .sorted()
+        .unwrap();
+        let project = Project::new().with_output_dir(root.join("out"));
+
+        let report = plan.execute(BuildCache::from_project(project));
+        let units: Vec<(Status, Option<u64>, bool)> = report
+            .units()
+            .into_iter()
+            .map(|unit| (unit.status, unit.size, unit.error.is_some()))
+            .collect();
+        assert_eq!(
+            units,
+            vec![
+                (Status::Built, None, false),
+                (Status::Failed, None, true),
+                (Status::Blocked, None, false),
+                (Status::Built, Some(5), false),
+            ]
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
     }
 }
@@ -0,0 +1,207 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use crate::build_graph::BuildPlan;
+use crate::build_rules::CompilationUnit;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GraphFormat {
+    Tree,
+    Dot,
+    Json,
+}
+
+impl FromStr for GraphFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<GraphFormat, String> {
+        match format {
+            "tree" => Ok(GraphFormat::Tree),
+            "dot" => Ok(GraphFormat::Dot),
+            "json" => Ok(GraphFormat::Json),
+            _ => Err(format!(
+                "Unknown graph format {:?}, try one of: tree, dot, json",
+                format
+            )),
+        }
+    }
+}
+
+/// Whether a unit is going to run. Plans that were not diffed against the
+/// last build don't know yet.
+fn status(cunit: &CompilationUnit, diffed: bool) -> &'static str {
+    match cunit {
+        CompilationUnit::CacheHit { .. } => "cache-hit",
+        _ if diffed => "rebuild",
+        _ => "planned",
+    }
+}
+
+/// A unit on a single line: its rule, what it reads and what it writes.
+pub fn describe(cunit: &CompilationUnit) -> String {
+    let inputs = cunit.inputs();
+    if inputs.is_empty() {
+        format!("{} {}", cunit.rule(), cunit.path().display())
+    } else {
+        let inputs: Vec<String> = inputs.iter().map(|i| i.display().to_string()).collect();
+        format!(
+            "{} {} -> {}",
+            cunit.rule(),
+            inputs.join(", "),
+            cunit.path().display()
+        )
+    }
+}
+
+fn dependents(plan: &BuildPlan) -> Vec<Vec<usize>> {
+    let mut dependents = vec![vec![]; plan.units().count()];
+    for unit in 0..dependents.len() {
+        for dependency in plan.dependencies(unit) {
+            dependents[dependency].push(unit);
+        }
+    }
+    dependents
+}
+
+/// The plan as an indented tree, with every unit under the units it depends
+/// on. Units that depend on more than one are shown in full only once.
+pub fn to_tree(plan: &BuildPlan, diffed: bool) -> String {
+    fn walk(
+        out: &mut String,
+        lines: &[(String, &str)],
+        dependents: &[Vec<usize>],
+        shown: &mut HashSet<usize>,
+        unit: usize,
+        depth: usize,
+    ) {
+        let (line, status) = &lines[unit];
+        let indent = "  ".repeat(depth);
+        if !shown.insert(unit) {
+            writeln!(out, "{}{} (see above)", indent, line).unwrap();
+            return;
+        }
+        writeln!(out, "{}{} [{}]", indent, line, status).unwrap();
+        for dependent in &dependents[unit] {
+            walk(out, lines, dependents, shown, *dependent, depth + 1);
+        }
+    }
+
+    let lines: Vec<(String, &str)> = plan
+        .units()
+        .map(|cunit| (describe(cunit), status(cunit, diffed)))
+        .collect();
+    let dependents = dependents(plan);
+    let mut shown = HashSet::new();
+    let mut out = String::new();
+    for unit in 0..lines.len() {
+        if plan.dependencies(unit).next().is_none() {
+            walk(&mut out, &lines, &dependents, &mut shown, unit, 0);
+        }
+    }
+    out
+}
+
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// The plan as a Graphviz graph, with arrows going from every unit to the
+/// units that depend on it. Cache hits are greyed out.
+pub fn to_dot(plan: &BuildPlan, diffed: bool) -> String {
+    let mut out = String::from("digraph build {\n  node [shape=box, fontname=\"monospace\"];\n");
+    for (unit, cunit) in plan.units().enumerate() {
+        let mut label = vec![cunit.rule().to_string()];
+        label.extend(cunit.inputs().iter().map(|i| i.display().to_string()));
+        label.push(format!("-> {}", cunit.path().display()));
+        label.push(format!("({})", status(cunit, diffed)));
+        let label: Vec<String> = label.iter().map(|line| dot_escape(line)).collect();
+        let style = match cunit {
+            CompilationUnit::CacheHit { .. } => ", style=dashed, color=gray, fontcolor=gray",
+            _ => "",
+        };
+        writeln!(
+            out,
+            "  u{} [label=\"{}\"{}];",
+            unit,
+            label.join("\\n"),
+            style
+        )
+        .unwrap();
+    }
+    for unit in 0..plan.units().count() {
+        for dependency in plan.dependencies(unit) {
+            writeln!(out, "  u{} -> u{};", dependency, unit).unwrap();
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// The plan as JSON, with every unit and the ids of the units it depends on.
+pub fn to_json(plan: &BuildPlan, diffed: bool) -> serde_json::Value {
+    let units: Vec<serde_json::Value> = plan
+        .units()
+        .enumerate()
+        .map(|(unit, cunit)| {
+            serde_json::json!({
+                "id": unit,
+                "rule": cunit.rule(),
+                "inputs": cunit.inputs(),
+                "outputs": vec![cunit.path()],
+                "status": status(cunit, diffed),
+                "dependencies": plan.dependencies(unit).collect::<Vec<usize>>(),
+            })
+        })
+        .collect();
+    serde_json::json!({ "units": units })
+}
+
+pub fn export(plan: &BuildPlan, format: GraphFormat, diffed: bool) -> String {
+    match format {
+        GraphFormat::Tree => to_tree(plan, diffed),
+        GraphFormat::Dot => to_dot(plan, diffed),
+        GraphFormat::Json => format!("{:#}\n", to_json(plan, diffed)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_export() {
+        let copy = |name: &str| CompilationUnit::Copy {
+            input: PathBuf::from(name),
+            output: PathBuf::from("out").join(name),
+        };
+        let plan = BuildPlan::start_with(CompilationUnit::CreateDir {
+            path: PathBuf::from("out"),
+        })
+        .and_then(vec![
+            BuildPlan::start_with(copy("a")).and_then(vec![BuildPlan::start_with(copy("c"))]),
+            BuildPlan::start_with(copy("b")).and_then(vec![BuildPlan::start_with(copy("c"))]),
+        ])
+        .sorted()
+        .unwrap()
+        .diff(|cunit| cunit.path() != Path::new("out/b"));
+
+        assert_eq!(
+            to_tree(&plan, true),
+            "create-dir out [cache-hit]\n\
+             \x20 copy a -> out/a [cache-hit]\n\
+             \x20   copy c -> out/c [rebuild]\n\
+             \x20 copy b -> out/b [rebuild]\n\
+             \x20   copy c -> out/c (see above)\n"
+        );
+
+        let dot = to_dot(&plan, true);
+        assert!(dot.contains("  u1 [label=\"copy\\na\\n-> out/a\\n(cache-hit)\", style=dashed"));
+        assert!(dot.contains("  u1 -> u3;\n  u2 -> u3;\n"));
+
+        let json = to_json(&plan, true);
+        assert_eq!(json["units"][3]["dependencies"], serde_json::json!([1, 2]));
+        assert_eq!(json["units"][3]["status"], "rebuild");
+    }
+}
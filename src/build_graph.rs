@@ -1,85 +1,467 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
 
+use crate::build_export::describe;
+use crate::build_links;
+use crate::build_listings;
 use crate::build_rules::CompilationUnit;
-use crate::model::{Project, Sitefile};
+use crate::document::{Document, DRAFTS_DIR};
+use crate::highlight;
+use crate::link_checker::normalize;
+use crate::model::{Highlight, Project, Sitefile};
+use crate::sitemap::SitemapUrl;
 
-#[derive(Debug, Clone)]
-pub enum BuildPlan {
-    Node(CompilationUnit, Vec<BuildPlan>),
-    Leaf(CompilationUnit),
+#[derive(Debug)]
+pub enum PlanError {
+    InvalidPermalink {
+        input: PathBuf,
+        reason: String,
+    },
+    OutputCollision {
+        output: PathBuf,
+        units: Vec<String>,
+    },
+    MissingBaseUrl {
+        needed_by: PathBuf,
+        rule: String,
+    },
+    UnknownTheme {
+        site: PathBuf,
+        theme: String,
+    },
+    BrokenDocumentLink {
+        input: PathBuf,
+        link: String,
+        reason: String,
+    },
+    DependencyCycle {
+        units: Vec<PathBuf>,
+    },
+    OutsideProject {
+        input: PathBuf,
+    },
+}
+
+impl std::fmt::Display for PlanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlanError::InvalidPermalink { input, reason } => {
+                write!(f, "Invalid permalink for {:?}: {}", input, reason)
+            }
+            PlanError::OutputCollision { output, units } => write!(
+                f,
+                "Output {:?} would be written by more than one unit: {:?}",
+                output, units
+            ),
+            PlanError::MissingBaseUrl { needed_by, rule } => write!(
+                f,
+                "{:?} asks for a {}, but the project has no (base-url ...) in its {} file",
+                needed_by,
+                rule,
+                Project::file_name()
+            ),
+            PlanError::UnknownTheme { site, theme } => write!(
+                f,
+                "{:?} highlights code with an unknown theme {:?}, try one of: {}",
+                site,
+                theme,
+                highlight::themes().join(", ")
+            ),
+            PlanError::BrokenDocumentLink {
+                input,
+                link,
+                reason,
+            } => write!(f, "{:?} links to {:?}, which {}", input, link, reason),
+            PlanError::DependencyCycle { units } => {
+                write!(f, "These units end up depending on themselves: {:?}", units)
+            }
+            PlanError::OutsideProject { input } => {
+                write!(f, "{:?} is outside of the project root", input)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PlanError {}
+
+/// The compilation units in a build, and the units each of them depends on.
+/// A unit only runs after everything it depends on, and is rebuilt whenever
+/// any of them is. Units that show up more than once, like a folder that two
+/// sites write into, are planned only once.
+#[derive(Debug, Clone, Default)]
+pub struct BuildPlan {
+    units: Vec<CompilationUnit>,
+    dependencies: Vec<BTreeSet<usize>>,
+    heads: Vec<usize>,
+}
+
+fn hash_of(cunit: &CompilationUnit) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    cunit.hash(&mut hasher);
+    hasher.finish()
 }
 
 impl BuildPlan {
     pub fn start_with(cunit: CompilationUnit) -> BuildPlan {
-        BuildPlan::Leaf(cunit)
+        BuildPlan {
+            units: vec![cunit],
+            dependencies: vec![BTreeSet::new()],
+            heads: vec![0],
+        }
     }
 
-    pub fn and_then(self, tasks: Vec<BuildPlan>) -> BuildPlan {
-        match self {
-            BuildPlan::Leaf(cunit) => BuildPlan::Node(cunit, tasks),
-            BuildPlan::Node(cunit, _) => BuildPlan::Node(cunit, tasks),
+    /// Adds the units of other plans to this one, reusing the units both
+    /// already have, and returns where the heads of each of them ended up.
+    fn merge(&mut self, tasks: Vec<BuildPlan>) -> Vec<Vec<usize>> {
+        let mut known: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (index, cunit) in self.units.iter().enumerate() {
+            known.entry(hash_of(cunit)).or_default().push(index);
         }
+
+        let mut heads = vec![];
+        for task in tasks {
+            let mut indices = Vec::with_capacity(task.units.len());
+            for cunit in task.units {
+                let hash = hash_of(&cunit);
+                let same = known
+                    .get(&hash)
+                    .and_then(|same| same.iter().find(|i| self.units[**i] == cunit));
+                let index = match same {
+                    Some(index) => *index,
+                    None => {
+                        self.units.push(cunit);
+                        self.dependencies.push(BTreeSet::new());
+                        let index = self.units.len() - 1;
+                        known.entry(hash).or_default().push(index);
+                        index
+                    }
+                };
+                indices.push(index);
+            }
+            for (index, dependencies) in task.dependencies.into_iter().enumerate() {
+                let dependencies = dependencies.into_iter().map(|dep| indices[dep]);
+                self.dependencies[indices[index]].extend(dependencies);
+            }
+            heads.push(task.heads.into_iter().map(|head| indices[head]).collect());
+        }
+        heads
     }
 
-    pub fn deps(self) -> Vec<BuildPlan> {
-        match self {
-            BuildPlan::Leaf(_) => vec![],
-            BuildPlan::Node(_, deps) => deps,
+    /// Adds other plans that depend on the units this plan starts with.
+    pub fn and_then(mut self, tasks: Vec<BuildPlan>) -> BuildPlan {
+        for task_heads in self.merge(tasks) {
+            for task_head in task_heads {
+                for head in self.heads.clone() {
+                    self.add_dependency(task_head, head);
+                }
+            }
+        }
+        self
+    }
+
+    /// Adds other plans that don't depend on anything in this one.
+    pub fn and_also(mut self, tasks: Vec<BuildPlan>) -> BuildPlan {
+        self.merge(tasks);
+        self
+    }
+
+    /// Makes the unit at `unit` depend on the unit at `dependency`.
+    pub fn add_dependency(&mut self, unit: usize, dependency: usize) {
+        if unit != dependency {
+            self.dependencies[unit].insert(dependency);
         }
     }
 
-    pub fn map<F>(self, f: F) -> BuildPlan
+    /// Every unit in the plan. Once sorted, units come after everything they
+    /// depend on.
+    pub fn units(&self) -> impl Iterator<Item = &CompilationUnit> {
+        self.units.iter()
+    }
+
+    /// The indices of the units the unit at `unit` depends on.
+    pub fn dependencies(&self, unit: usize) -> impl Iterator<Item = usize> + '_ {
+        self.dependencies[unit].iter().copied()
+    }
+
+    /// Maps every unit in the plan, keeping what depends on what.
+    pub fn map<F>(self, mut f: F) -> BuildPlan
     where
-        F: Copy + FnOnce(CompilationUnit) -> CompilationUnit,
+        F: FnMut(CompilationUnit) -> CompilationUnit,
     {
-        match self {
-            BuildPlan::Node(cunit, deps) => {
-                let new_cunit = f(cunit.clone());
-                if new_cunit == cunit {
-                    BuildPlan::Node(new_cunit, deps)
-                } else {
-                    BuildPlan::Node(new_cunit, deps.into_iter().map(move |d| d.map(f)).collect())
+        BuildPlan {
+            units: self.units.into_iter().map(&mut f).collect(),
+            dependencies: self.dependencies,
+            heads: self.heads,
+        }
+    }
+
+    pub fn try_map<F, E>(self, mut f: F) -> Result<BuildPlan, E>
+    where
+        F: FnMut(CompilationUnit) -> Result<CompilationUnit, E>,
+    {
+        Ok(BuildPlan {
+            units: self
+                .units
+                .into_iter()
+                .map(&mut f)
+                .collect::<Result<Vec<CompilationUnit>, E>>()?,
+            dependencies: self.dependencies,
+            heads: self.heads,
+        })
+    }
+
+    fn dependents(&self) -> Vec<Vec<usize>> {
+        let mut dependents = vec![vec![]; self.units.len()];
+        for (unit, dependencies) in self.dependencies.iter().enumerate() {
+            for dependency in dependencies {
+                dependents[*dependency].push(unit);
+            }
+        }
+        dependents
+    }
+
+    /// The order units run in: every unit after the units it depends on, and
+    /// otherwise in the order they were planned in. Units that end up
+    /// depending on themselves never get their turn, and are left out.
+    fn order(&self) -> Vec<usize> {
+        let dependents = self.dependents();
+        let mut waiting_on: Vec<usize> = self.dependencies.iter().map(BTreeSet::len).collect();
+        let mut ready: BTreeSet<usize> = (0..self.units.len())
+            .filter(|unit| waiting_on[*unit] == 0)
+            .collect();
+        let mut order = Vec::with_capacity(self.units.len());
+        while let Some(unit) = ready.pop_first() {
+            order.push(unit);
+            for dependent in &dependents[unit] {
+                waiting_on[*dependent] -= 1;
+                if waiting_on[*dependent] == 0 {
+                    ready.insert(*dependent);
                 }
             }
-            BuildPlan::Leaf(cunit) => BuildPlan::Leaf(f(cunit)),
         }
+        order
     }
 
-    pub fn breadth_first_iter<'a>(&'a self) -> Box<dyn Iterator<Item = &CompilationUnit> + 'a> {
-        match self {
-            BuildPlan::Leaf(cunit) => Box::new(vec![cunit].into_iter()),
-            BuildPlan::Node(cunit, deps) => {
-                let root = vec![cunit].into_iter();
-                let deps = deps.iter().flat_map(BuildPlan::breadth_first_iter);
-                Box::new(root.chain(deps))
+    /// Puts every unit after the units it depends on, keeping them in the
+    /// order they were planned in otherwise, or fails if some units end up
+    /// depending on themselves.
+    pub fn sorted(self) -> Result<BuildPlan, PlanError> {
+        let order = self.order();
+        if order.len() < self.units.len() {
+            return Err(PlanError::DependencyCycle {
+                units: self.cycle(&order),
+            });
+        }
+
+        let mut position = vec![0; order.len()];
+        for (new, old) in order.iter().enumerate() {
+            position[*old] = new;
+        }
+        let dependencies = order
+            .iter()
+            .map(|old| {
+                self.dependencies[*old]
+                    .iter()
+                    .map(|dep| position[*dep])
+                    .collect()
+            })
+            .collect();
+        let heads = self.heads.iter().map(|head| position[*head]).collect();
+        let mut units: Vec<Option<CompilationUnit>> = self.units.into_iter().map(Some).collect();
+        Ok(BuildPlan {
+            units: order
+                .iter()
+                .map(|old| units[*old].take().unwrap())
+                .collect(),
+            dependencies,
+            heads,
+        })
+    }
+
+    /// The units that never got their turn, without the ones that are only
+    /// waiting on others.
+    fn cycle(&self, order: &[usize]) -> Vec<PathBuf> {
+        let dependents = self.dependents();
+        let mut left: BTreeSet<usize> = (0..self.units.len())
+            .filter(|unit| !order.contains(unit))
+            .collect();
+        loop {
+            let leaves: Vec<usize> = left
+                .iter()
+                .copied()
+                .filter(|unit| !dependents[*unit].iter().any(|d| left.contains(d)))
+                .collect();
+            if leaves.is_empty() {
+                break;
+            }
+            for leaf in leaves {
+                left.remove(&leaf);
             }
         }
+        left.into_iter()
+            .map(|unit| self.units[unit].path())
+            .collect()
+    }
+
+    /// Turns every unit that is still fresh into a cache hit. Every unit is
+    /// checked, in the order they run in, and a unit that depends on anything
+    /// that is rebuilt is rebuilt too, even when it looks fresh. Cache hits
+    /// are checked again, so a plan can be diffed more than once.
+    pub fn diff<F>(self, mut is_fresh: F) -> BuildPlan
+    where
+        F: FnMut(&CompilationUnit) -> bool,
+    {
+        let mut rebuilt = vec![true; self.units.len()];
+        for unit in self.order() {
+            let fresh = match &self.units[unit] {
+                CompilationUnit::CacheHit { unit } => is_fresh(unit),
+                cunit => is_fresh(cunit),
+            };
+            let forced = self.dependencies[unit].iter().any(|dep| rebuilt[*dep]);
+            rebuilt[unit] = !fresh || forced;
+        }
+
+        let mut rebuilt = rebuilt.into_iter();
+        self.map(|cunit| match (cunit, rebuilt.next().unwrap_or(true)) {
+            (CompilationUnit::CacheHit { unit }, true) => *unit,
+            (hit @ CompilationUnit::CacheHit { .. }, false) => hit,
+            (unit, true) => unit,
+            (unit, false) => CompilationUnit::CacheHit {
+                unit: Box::new(unit),
+            },
+        })
     }
 }
 
-fn plan_site(root: PathBuf, output_dir: PathBuf, files: &Vec<PathBuf>) -> Option<BuildPlan> {
-    let site = Sitefile::from_dir_path(root.clone())?;
-    let (docs, _): (Vec<String>, Vec<String>) = files
-        .iter()
-        .map(|p| p.file_name().unwrap())
-        .map(|p| String::from(p.to_str().unwrap()))
-        .partition(|p| p.ends_with("html") || p.ends_with("md"));
+/// Whether two paths lead to the same file, even when they don't exist (yet).
+pub(crate) fn is_same_path(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a.eq(&b),
+        _ => normalize(a).is_some() && normalize(a) == normalize(b),
+    }
+}
+
+/// Walks a site folder collecting every file in it, relative to the site
+/// root. Folders with their own `site` file are sites of their own, and are
+/// left out, as well as hidden folders and the output folder.
+fn walk_site(root: &Path, dir: &Path, excluded: &Path, files: &mut Vec<PathBuf>) {
+    let mut entries: Vec<PathBuf> = fs::read_dir(root.join(dir))
+        .expect("Could not read site folder")
+        .map(|e| e.unwrap().path())
+        .collect();
+    entries.sort();
+
+    for path in entries {
+        let relative = dir.join(path.file_name().unwrap());
+        if !path.is_dir() {
+            files.push(relative);
+            continue;
+        }
+
+        let is_hidden = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with('.'));
+        if is_hidden || is_same_path(&path, excluded) || path.join(Sitefile::name()).exists() {
+            continue;
+        }
+
+        walk_site(root, &relative, excluded, files);
+    }
+}
+
+fn plan_site(
+    root: PathBuf,
+    output_dir: PathBuf,
+    project: &Project,
+    files: &[PathBuf],
+) -> Result<Option<BuildPlan>, PlanError> {
+    let site = match Sitefile::from_dir_path(root.clone()) {
+        Some(site) => site,
+        None => return Ok(None),
+    };
+
+    let project_output_dir = project.clone().output_dir();
+    let files = if site.clone().recursive() {
+        let mut files = vec![];
+        walk_site(&root, Path::new(""), &project_output_dir, &mut files);
+        files
+    } else {
+        let mut files: Vec<PathBuf> = files
+            .iter()
+            .map(|p| PathBuf::from(p.file_name().unwrap()))
+            .collect();
+        if root.join(DRAFTS_DIR).is_dir() {
+            walk_site(
+                &root,
+                Path::new(DRAFTS_DIR),
+                &project_output_dir,
+                &mut files,
+            );
+        }
+        files
+    };
+
+    let (docs, _): (Vec<PathBuf>, Vec<PathBuf>) = files.iter().cloned().partition(|p| {
+        let p = p.to_str().unwrap();
+        p.ends_with("html") || p.ends_with("md")
+    });
 
     let template = site.clone().template();
+    let permalink = site.clone().permalink();
+    let collection = site.clone().collection();
+    let taxonomies = site.clone().taxonomies();
+    let archive = site.clone().archive();
+    let feed = site.clone().feed();
+    let content_index = site.clone().content_index();
+    let search = site.clone().search();
+    let highlight = site.clone().highlight();
+    let markdown = site.clone().markdown().or(project.clone().markdown());
+    let toc = site.clone().toc();
+    if let Some(theme) = highlight.clone().map(Highlight::theme) {
+        if highlight::find_theme(&theme).is_none() {
+            return Err(PlanError::UnknownTheme {
+                site: root.join(Sitefile::name()),
+                theme,
+            });
+        }
+    }
+    if feed.is_some() && project.clone().base_url().is_none() {
+        return Err(PlanError::MissingBaseUrl {
+            needed_by: root.join(Sitefile::name()),
+            rule: "feed".to_string(),
+        });
+    }
+
+    // Templates are not documents of their own.
+    let templates: Vec<PathBuf> = template
+        .iter()
+        .cloned()
+        .chain(collection.iter().filter_map(|c| c.clone().template()))
+        .chain(archive.iter().filter_map(|a| a.clone().template()))
+        .chain(taxonomies.iter().flat_map(|t| {
+            let t = t.clone();
+            t.clone().template().into_iter().chain(t.index_template())
+        }))
+        .collect();
 
-    let assets: Vec<BuildPlan> = site
+    let assets: Vec<CompilationUnit> = site
         .assets()
-        .unwrap_or_else(|| vec![])
+        .unwrap_or_default()
         .into_iter()
         .flat_map(|p| {
+            // HTML documents get compiled into the very file a copy would
+            // write, so they are not assets too.
             if p.to_str().unwrap().eq(".") {
-                fs::read_dir(root.clone())
-                    .expect("Could not read root folder")
-                    .map(|e| e.unwrap().path())
-                    .filter(|p| !p.is_dir())
-                    .map(|p| p.file_name().unwrap().to_str().unwrap().to_string())
-                    .map(PathBuf::from)
+                files
+                    .iter()
+                    .filter(|f| !f.starts_with(DRAFTS_DIR))
+                    .filter(|f| !(docs.contains(f) && f.extension().is_some_and(|e| e == "html")))
+                    .cloned()
                     .collect()
             } else {
                 vec![p]
@@ -96,23 +478,89 @@ fn plan_site(root: PathBuf, output_dir: PathBuf, files: &Vec<PathBuf>) -> Option
             input: root.clone().join(a.clone()),
             output: output_dir.clone().join(a),
         })
-        .map(BuildPlan::start_with)
         .collect();
 
     let docs = docs
         .into_iter()
-        .filter(|d| {
-            if let Some(template) = &template {
-                !PathBuf::from(d).eq(template)
-            } else {
-                true
-            }
-        })
+        .filter(|d| !templates.contains(d))
         .map(|d| {
-            let output = output_dir.clone().join(d.clone()).with_extension("html");
+            let doc = Document::from_path(&root, d);
+            let output = doc.output_path(permalink.as_deref()).map_err(|reason| {
+                PlanError::InvalidPermalink {
+                    input: doc.input().to_path_buf(),
+                    reason,
+                }
+            })?;
+            Ok((doc, output_dir.join(output)))
+        })
+        .collect::<Result<Vec<(Document, PathBuf)>, PlanError>>()?;
+
+    // Drafts and documents scheduled for later are only built when asked
+    // for, and anything they left behind from an earlier build is removed.
+    let now = chrono::Local::now().naive_local();
+    let (docs, unpublished): (Vec<_>, Vec<_>) =
+        docs.into_iter()
+            .partition(|(doc, _): &(Document, PathBuf)| {
+                (!doc.is_draft() || project.clone().drafts())
+                    && (!doc.is_future(now) || project.clone().future())
+            });
+
+    let listings: Vec<CompilationUnit> = collection
+        .iter()
+        .flat_map(|collection| {
+            build_listings::plan_collection(project, collection, &root, &output_dir, &docs)
+        })
+        .chain(taxonomies.iter().flat_map(|taxonomy| {
+            build_listings::plan_taxonomy(project, taxonomy, &root, &output_dir, &docs)
+        }))
+        .chain(archive.iter().flat_map(|archive| {
+            build_listings::plan_archive(project, archive, &root, &output_dir, &docs)
+        }))
+        .chain(feed.iter().flat_map(|feed| {
+            build_listings::plan_feed(project, feed, &markdown, &toc, &root, &output_dir, &docs)
+        }))
+        .chain(search.iter().flat_map(|search| {
+            build_listings::plan_search(project, search, &markdown, &output_dir, &docs)
+        }))
+        .chain(content_index.map(|output| {
+            build_listings::plan_content_index(project, output_dir.join(output), &docs)
+        }))
+        .collect();
+
+    // Every folder we are about to write into gets created before anything
+    // else in the site, since recursive sites and permalinks can nest outputs.
+    let mut dirs: Vec<PathBuf> = docs
+        .iter()
+        .map(|(_, output)| output.clone())
+        .chain(assets.iter().filter_map(CompilationUnit::output))
+        .chain(listings.iter().filter_map(CompilationUnit::output))
+        .filter_map(|output| output.parent().map(Path::to_path_buf))
+        .filter(|dir| !dir.eq(&output_dir))
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+
+    let docs = docs
+        .into_iter()
+        .map(|(doc, output)| {
+            let banner = if doc.is_draft() {
+                Some("Draft".to_string())
+            } else if doc.is_future(now) {
+                doc.date()
+                    .map(|date| format!("Scheduled for {}", date.format("%Y-%m-%d")))
+            } else {
+                None
+            };
             let cunit = CompilationUnit::Compile {
-                input: root.clone().join(d),
+                input: doc.input().to_path_buf(),
                 output: output.clone(),
+                banner,
+                markdown: markdown.clone(),
+                highlight: highlight.clone(),
+                toc: toc.clone(),
+                links: BTreeMap::new(),
+                wiki_links: BTreeMap::new(),
+                dependencies: vec![],
             };
             let compile = BuildPlan::start_with(cunit);
 
@@ -122,6 +570,8 @@ fn plan_site(root: PathBuf, output_dir: PathBuf, files: &Vec<PathBuf>) -> Option
                         input: output.clone(),
                         output,
                         template: root.clone().join(template),
+                        toc: toc.clone(),
+                        backlinks: vec![],
                     };
                     let template = vec![BuildPlan::start_with(cunit)];
                     compile.and_then(template)
@@ -134,7 +584,11 @@ fn plan_site(root: PathBuf, output_dir: PathBuf, files: &Vec<PathBuf>) -> Option
     let create_dir = CompilationUnit::CreateDir {
         path: output_dir.clone(),
     };
-    let mut copy_and_compile_docs = vec![];
+
+    let mut copy_and_compile_docs: Vec<BuildPlan> = dirs
+        .into_iter()
+        .map(|path| BuildPlan::start_with(CompilationUnit::CreateDir { path }))
+        .collect();
 
     if let Some(template) = &template {
         let copy_template = CompilationUnit::Copy {
@@ -149,46 +603,470 @@ fn plan_site(root: PathBuf, output_dir: PathBuf, files: &Vec<PathBuf>) -> Option
     };
 
     for asset in assets {
-        copy_and_compile_docs.push(asset)
+        copy_and_compile_docs.push(BuildPlan::start_with(asset))
+    }
+
+    for listing in listings {
+        copy_and_compile_docs.push(BuildPlan::start_with(listing))
+    }
+
+    for (_, output) in unpublished {
+        copy_and_compile_docs.push(BuildPlan::start_with(CompilationUnit::Delete {
+            path: output,
+        }))
     }
 
-    Some(BuildPlan::start_with(create_dir).and_then(copy_and_compile_docs))
+    Ok(Some(
+        BuildPlan::start_with(create_dir).and_then(copy_and_compile_docs),
+    ))
 }
 
-fn find_sites(root: PathBuf, output_dir: PathBuf) -> Vec<BuildPlan> {
+fn find_sites(
+    root: PathBuf,
+    output_dir: PathBuf,
+    project: &Project,
+) -> Result<Vec<BuildPlan>, PlanError> {
     if root.is_dir() {
-        let (files, dirs) = fs::read_dir(root.clone())
+        let (files, dirs): (Vec<PathBuf>, Vec<PathBuf>) = fs::read_dir(root.clone())
             .unwrap()
             .map(|e| e.unwrap().path())
             .partition(|p| !p.is_dir());
 
-        let root_graph = plan_site(root, output_dir.clone(), &files);
+        let root_graph = plan_site(root, output_dir.clone(), project, &files)?;
 
-        let mut subsites: Vec<BuildPlan> = dirs
-            .into_iter()
-            .filter(|subroot| !subroot.eq(&output_dir.clone()))
-            .flat_map(|subroot| {
-                find_sites(
-                    subroot.clone(),
-                    output_dir.clone().join(subroot.file_name().unwrap()),
-                )
-            })
-            .collect();
+        let mut subsites: Vec<BuildPlan> = vec![];
+        for subroot in dirs {
+            if subroot.eq(&output_dir) {
+                continue;
+            }
+            subsites.extend(find_sites(
+                subroot.clone(),
+                output_dir.clone().join(subroot.file_name().unwrap()),
+                project,
+            )?);
+        }
 
         if let Some(rg) = root_graph {
             subsites.push(rg);
         }
 
-        subsites
+        Ok(subsites)
     } else {
-        vec![]
+        Ok(vec![])
+    }
+}
+
+/// Makes sure no two different inputs are written to the same output, which
+/// custom permalinks make rather easy to do.
+fn check_collisions(plan: &BuildPlan) -> Result<(), PlanError> {
+    let mut units_by_output: HashMap<PathBuf, &CompilationUnit> = HashMap::new();
+    for cunit in plan.units() {
+        let output = match cunit.output() {
+            Some(output) => output,
+            None => continue,
+        };
+        // Identical units are planned only once, so any other unit writing
+        // the same output would overwrite it, unless it reads it too, like
+        // templates do with the pages they wrap.
+        if cunit.inputs().contains(&output) {
+            continue;
+        }
+        if let Some(other) = units_by_output.insert(output.clone(), cunit) {
+            return Err(PlanError::OutputCollision {
+                output,
+                units: vec![describe(other), describe(cunit)],
+            });
+        }
+    }
+    Ok(())
+}
+
+fn last_modified(path: &Path) -> Option<NaiveDateTime> {
+    let modified = fs::metadata(path).and_then(|meta| meta.modified()).ok()?;
+    Some(DateTime::<Utc>::from(modified).naive_utc())
+}
+
+/// Adds a `sitemap.xml` listing every page in the project, and a `robots.txt`
+/// pointing at it, when the project asks for them. Pages are dated by their
+/// document's date, or by when their sources were last modified.
+fn plan_sitemap(project: &Project, plan: BuildPlan) -> Result<BuildPlan, PlanError> {
+    if !project.clone().sitemap() && !project.clone().robots() {
+        return Ok(plan);
+    }
+    if project.clone().base_url().is_none() {
+        return Err(PlanError::MissingBaseUrl {
+            needed_by: project.clone().root().join(Project::file_name()),
+            rule: "sitemap".to_string(),
+        });
+    }
+
+    // Templates are copied along with the pages that use them, but they are
+    // not pages of their own.
+    let templates: HashSet<&PathBuf> = plan
+        .units()
+        .filter_map(|cunit| match cunit {
+            CompilationUnit::Template { template, .. } => Some(template),
+            CompilationUnit::Generate { template, .. } => template.as_ref(),
+            _ => None,
+        })
+        .collect();
+
+    let mut pages: HashMap<PathBuf, Option<NaiveDateTime>> = HashMap::new();
+    for cunit in plan.units() {
+        let (output, lastmod) = match cunit {
+            // Drafts and scheduled documents are not meant to be found yet.
+            CompilationUnit::Compile {
+                input,
+                output,
+                banner: None,
+                ..
+            } => {
+                let doc = Document::from_path(Path::new(""), input.clone());
+                if doc.front_matter().get("sitemap") == Some("false") {
+                    continue;
+                }
+                (output, doc.date().or_else(|| last_modified(input)))
+            }
+            CompilationUnit::Copy { input, .. } if templates.contains(input) => continue,
+            CompilationUnit::Copy { input, output } => (output, last_modified(input)),
+            CompilationUnit::Generate { inputs, output, .. } => (
+                output,
+                inputs.iter().filter_map(|input| last_modified(input)).max(),
+            ),
+            _ => continue,
+        };
+        if output.extension().is_some_and(|ext| ext == "html") {
+            pages.insert(output.clone(), lastmod);
+        }
+    }
+
+    let mut urls: Vec<SitemapUrl> = pages
+        .into_iter()
+        .filter_map(|(output, lastmod)| {
+            Some(SitemapUrl {
+                loc: project.absolute_url_for(&output)?,
+                lastmod: lastmod.map(|date| date.format("%Y-%m-%d").to_string()),
+            })
+        })
+        .collect();
+    urls.sort_by(|a, b| a.loc.cmp(&b.loc));
+
+    let output_dir = project.clone().output_dir();
+    let sitemap = output_dir.join("sitemap.xml");
+    let mut units = vec![];
+    if project.clone().robots() {
+        units.push(CompilationUnit::Robots {
+            output: output_dir.join("robots.txt"),
+            sitemap: project
+                .absolute_url_for(&sitemap)
+                .filter(|_| project.clone().sitemap()),
+        });
+    }
+    if project.clone().sitemap() {
+        units.push(CompilationUnit::Sitemap {
+            output: sitemap,
+            urls,
+        });
+    }
+
+    Ok(plan.and_also(units.into_iter().map(BuildPlan::start_with).collect()))
+}
+
+/// Makes every unit depend on the units that write what it reads, and on the
+/// folder it writes into. Units that list documents, like indexes and feeds,
+/// depend on every document they list.
+fn plan_dependencies(mut plan: BuildPlan) -> BuildPlan {
+    let mut producers: HashMap<PathBuf, Vec<usize>> = HashMap::new();
+    for (index, cunit) in plan.units.iter().enumerate() {
+        let paths = match cunit {
+            CompilationUnit::CreateDir { path } => vec![path.clone()],
+            CompilationUnit::Compile { input, output, .. } => vec![input.clone(), output.clone()],
+            cunit => cunit.output().into_iter().collect(),
+        };
+        for path in paths {
+            producers.entry(path).or_default().push(index);
+        }
+    }
+
+    for index in 0..plan.units.len() {
+        let cunit = &plan.units[index];
+        let folder = match cunit {
+            CompilationUnit::CreateDir { path } => Some(path.clone()),
+            cunit => cunit.output(),
+        }
+        .and_then(|path| path.parent().map(Path::to_path_buf));
+        let needs: Vec<usize> = cunit
+            .inputs()
+            .into_iter()
+            .chain(folder)
+            .filter_map(|path| producers.get(&path))
+            .flatten()
+            .copied()
+            .collect();
+        for dependency in needs {
+            plan.add_dependency(index, dependency);
+        }
     }
+    plan
 }
 
-pub fn plan_build(project: Project) -> BuildPlan {
+pub fn plan_build(project: Project) -> Result<BuildPlan, PlanError> {
     let create_dir = CompilationUnit::CreateDir {
         path: project.clone().output_dir(),
     };
-    let build_sites = find_sites(project.clone().root(), project.output_dir());
-    BuildPlan::start_with(create_dir).and_then(build_sites)
+    let build_sites = find_sites(
+        project.clone().root(),
+        project.clone().output_dir(),
+        &project,
+    )?;
+    let plan = BuildPlan::start_with(create_dir).and_then(build_sites);
+    let plan = plan_sitemap(&project, plan)?;
+    let plan = build_links::link_documents(&project, plan)?;
+    check_collisions(&plan)?;
+    plan_dependencies(plan).sorted()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Toc;
+    use crate::template::Value;
+
+    fn create_dir(path: &str) -> CompilationUnit {
+        CompilationUnit::CreateDir {
+            path: PathBuf::from(path),
+        }
+    }
+
+    fn copy(input: &str, output: &str) -> CompilationUnit {
+        CompilationUnit::Copy {
+            input: PathBuf::from(input),
+            output: PathBuf::from(output),
+        }
+    }
+
+    #[test]
+    fn test_shared_units_are_planned_once() {
+        let style = || BuildPlan::start_with(copy("style.css", "out/style.css"));
+        let plan = BuildPlan::start_with(create_dir("out"))
+            .and_then(vec![
+                BuildPlan::start_with(create_dir("out/a")).and_then(vec![style()]),
+                BuildPlan::start_with(create_dir("out/b")).and_then(vec![style()]),
+            ])
+            .sorted()
+            .unwrap();
+
+        let units: Vec<&CompilationUnit> = plan.units().collect();
+        assert_eq!(
+            units,
+            vec![
+                &create_dir("out"),
+                &create_dir("out/a"),
+                &create_dir("out/b"),
+                &copy("style.css", "out/style.css"),
+            ]
+        );
+        assert_eq!(plan.dependencies(3).collect::<Vec<usize>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_collisions() {
+        let generate = |inputs: &[&str], template: Option<&str>| CompilationUnit::Generate {
+            inputs: inputs.iter().map(PathBuf::from).collect(),
+            output: PathBuf::from("out/2019/index.html"),
+            template: template.map(PathBuf::from),
+            context: Value::map(),
+        };
+        let plan = |units: Vec<CompilationUnit>| {
+            BuildPlan::start_with(create_dir("out"))
+                .and_then(units.into_iter().map(BuildPlan::start_with).collect())
+        };
+
+        let shared = plan(vec![copy("a.css", "out/a.css"), copy("a.css", "out/a.css")]);
+        assert!(check_collisions(&shared).is_ok());
+
+        for units in [
+            vec![copy("a.md", "out/a.html"), copy("b.md", "out/a.html")],
+            vec![generate(&["a.md"], None), generate(&["b.md"], None)],
+            vec![
+                generate(&["a.md"], Some("index.html")),
+                generate(&["b.md"], Some("index.html")),
+            ],
+        ] {
+            match check_collisions(&plan(units)) {
+                Err(PlanError::OutputCollision { units, .. }) => assert_eq!(units.len(), 2),
+                other => panic!("Expected a collision, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_sitemap() {
+        let project = Project::new()
+            .with_output_dir(PathBuf::from("out"))
+            .with_base_url("https://example.com/".to_string())
+            .with_sitemap(true)
+            .with_robots(true);
+        let plan = BuildPlan::start_with(create_dir("out")).and_then(vec![
+            BuildPlan::start_with(copy("blog/template.html", "out/blog/template.html")),
+            BuildPlan::start_with(copy("blog/style.css", "out/blog/style.css")),
+            BuildPlan::start_with(copy("blog/page.html", "out/blog/page.html")),
+            BuildPlan::start_with(CompilationUnit::Template {
+                input: PathBuf::from("out/blog/a.html"),
+                output: PathBuf::from("out/blog/a.html"),
+                template: PathBuf::from("blog/template.html"),
+                toc: Toc::from_sexp(&[]),
+                backlinks: vec![],
+            }),
+        ]);
+
+        let plan = plan_sitemap(&project, plan).unwrap();
+        let units: Vec<&CompilationUnit> = plan.units().collect();
+        assert_eq!(
+            units[units.len() - 2..],
+            [
+                &CompilationUnit::Robots {
+                    output: PathBuf::from("out/robots.txt"),
+                    sitemap: Some("https://example.com/sitemap.xml".to_string()),
+                },
+                &CompilationUnit::Sitemap {
+                    output: PathBuf::from("out/sitemap.xml"),
+                    urls: vec![SitemapUrl {
+                        loc: "https://example.com/blog/page.html".to_string(),
+                        lastmod: None,
+                    }],
+                },
+            ]
+        );
+
+        let project = project.with_sitemap(false);
+        let plan = plan_sitemap(&project, BuildPlan::start_with(create_dir("out"))).unwrap();
+        assert_eq!(
+            plan.units().last(),
+            Some(&CompilationUnit::Robots {
+                output: PathBuf::from("out/robots.txt"),
+                sitemap: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_dependencies_come_first() {
+        let plan = BuildPlan::start_with(copy("index.html", "out/index.html"))
+            .and_also(vec![BuildPlan::start_with(create_dir("out"))]);
+        let plan = plan_dependencies(plan).sorted().unwrap();
+        let units: Vec<&CompilationUnit> = plan.units().collect();
+        assert_eq!(
+            units,
+            vec![&create_dir("out"), &copy("index.html", "out/index.html")]
+        );
+    }
+
+    #[test]
+    fn test_cycles() {
+        let plan = BuildPlan::start_with(create_dir("out"))
+            .and_then(vec![BuildPlan::start_with(copy("a", "out/a"))
+                .and_then(vec![BuildPlan::start_with(copy("out/a", "out/b"))])]);
+        let mut plan = plan.and_also(vec![BuildPlan::start_with(copy("z", "out/z"))]);
+        plan.add_dependency(1, 2);
+        plan.add_dependency(3, 2);
+
+        match plan.sorted() {
+            Err(PlanError::DependencyCycle { units }) => {
+                assert_eq!(units, vec![PathBuf::from("out/a"), PathBuf::from("out/b")])
+            }
+            other => panic!("expected a cycle, got {:?}", other),
+        }
+    }
+
+    fn leaf(name: &str) -> BuildPlan {
+        BuildPlan::start_with(copy(name, &format!("out/{}", name)))
+    }
+
+    /// Plans shaped like the ones sites end up with.
+    fn fixtures() -> Vec<BuildPlan> {
+        let chain = leaf("dir").and_then(vec![
+            leaf("template").and_then(vec![leaf("a.md").and_then(vec![leaf("a.html")])])
+        ]);
+
+        let mut site = leaf("dir").and_then(vec![
+            leaf("posts"),
+            leaf("template").and_then(vec![
+                leaf("a.md").and_then(vec![leaf("a.html")]),
+                leaf("b.md").and_then(vec![leaf("b.html")]),
+            ]),
+            leaf("style.css"),
+            leaf("index.html"),
+        ]);
+        // The index lists both posts.
+        site.add_dependency(8, 3);
+        site.add_dependency(8, 5);
+
+        let diamond = leaf("dir").and_then(vec![
+            leaf("a").and_then(vec![leaf("shared").and_then(vec![leaf("last")])]),
+            leaf("b").and_then(vec![leaf("shared").and_then(vec![leaf("last")])]),
+        ]);
+
+        // Dependencies planned after the units that need them.
+        let mut unsorted = leaf("page").and_also(vec![leaf("doc"), leaf("dir")]);
+        unsorted.add_dependency(0, 1);
+        unsorted.add_dependency(1, 2);
+
+        vec![chain, site, diamond, unsorted]
+    }
+
+    /// What should be rebuilt when the units in `changed` are: those, and
+    /// everything that depends on them in any way.
+    fn expected_rebuild(plan: &BuildPlan, changed: &[bool], unit: usize) -> bool {
+        changed[unit]
+            || plan
+                .dependencies(unit)
+                .any(|dep| expected_rebuild(plan, changed, dep))
+    }
+
+    #[test]
+    fn test_diff_rebuilds_every_dependent() {
+        for plan in fixtures() {
+            let units: Vec<CompilationUnit> = plan.units().cloned().collect();
+            for changes in 0..(1 << units.len()) {
+                let changed: Vec<bool> = (0..units.len())
+                    .map(|unit| changes & (1 << unit) != 0)
+                    .collect();
+                let mut checked = vec![false; units.len()];
+                let diffed = plan.clone().diff(|cunit| {
+                    let unit = units.iter().position(|u| u == cunit).unwrap();
+                    checked[unit] = true;
+                    !changed[unit]
+                });
+
+                assert!(checked.iter().all(|checked| *checked));
+                for (unit, cunit) in diffed.units().enumerate() {
+                    let rebuilt = !matches!(cunit, CompilationUnit::CacheHit { .. });
+                    assert_eq!(
+                        rebuilt,
+                        expected_rebuild(&plan, &changed, unit),
+                        "{:?} with changes in {:?}",
+                        cunit,
+                        changed
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_diff_undoes_cache_hits() {
+        let plan = leaf("template").and_then(vec![leaf("a.md")]);
+        let plan = plan.diff(|_| true);
+        assert!(plan
+            .units()
+            .all(|cunit| matches!(cunit, CompilationUnit::CacheHit { .. })));
+
+        let template = copy("template", "out/template");
+        let plan = plan.diff(|cunit| *cunit != template);
+        assert_eq!(
+            plan.units().collect::<Vec<&CompilationUnit>>(),
+            vec![&template, &copy("a.md", "out/a.md")]
+        );
+    }
 }
@@ -0,0 +1,275 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::build_graph::{BuildPlan, PlanError};
+use crate::build_rules::CompilationUnit;
+use crate::doc_links::{self, Backlink};
+use crate::document::{slugify, Document};
+use crate::feed::FeedEntry;
+use crate::link_checker::{normalize, percent_decode};
+use crate::model::Project;
+use crate::search::SearchDocument;
+
+/// A document compiled somewhere in the project, that others can link to.
+struct Page {
+    input: PathBuf,
+    output: PathBuf,
+    title: String,
+    source: String,
+    opts: comrak::ComrakOptions,
+}
+
+/// The links of a document, resolved to the URLs they point at.
+#[derive(Clone, Default)]
+struct Links {
+    links: BTreeMap<String, String>,
+    wiki_links: BTreeMap<String, String>,
+    dependencies: BTreeSet<PathBuf>,
+}
+
+/// Every compiled document in the project, by title and by slug, so wiki
+/// links can find them.
+struct Index<'a> {
+    project: &'a Project,
+    root: PathBuf,
+    pages: HashMap<PathBuf, Page>,
+    titles: HashMap<String, Vec<PathBuf>>,
+    slugs: HashMap<String, Vec<PathBuf>>,
+}
+
+impl<'a> Index<'a> {
+    fn new(project: &'a Project, plan: &BuildPlan) -> Result<Index<'a>, PlanError> {
+        let root = project.clone().root();
+        let mut index = Index {
+            project,
+            root: fs::canonicalize(&root).unwrap_or(root),
+            pages: HashMap::new(),
+            titles: HashMap::new(),
+            slugs: HashMap::new(),
+        };
+        for unit in plan.units() {
+            let (input, output, markdown) = match unit {
+                CompilationUnit::Compile {
+                    input,
+                    output,
+                    markdown,
+                    ..
+                } => (input, output, markdown),
+                _ => continue,
+            };
+            let key = index.key(input).ok_or_else(|| PlanError::OutsideProject {
+                input: input.clone(),
+            })?;
+            let source = fs::read_to_string(input).unwrap_or_default();
+            let name = PathBuf::from(input.file_name().unwrap_or_default());
+            let doc = Document::from_source(input.clone(), name, &source);
+            index
+                .titles
+                .entry(doc.title().to_lowercase())
+                .or_default()
+                .push(key.clone());
+            index.slugs.entry(doc.slug()).or_default().push(key.clone());
+            index.pages.insert(
+                key,
+                Page {
+                    input: input.clone(),
+                    output: output.clone(),
+                    title: doc.title(),
+                    source,
+                    opts: markdown.comrak_options(),
+                },
+            );
+        }
+        Ok(index)
+    }
+
+    /// Where a path in the project is, starting from the canonical project
+    /// root, so documents can be found however the root was given to us.
+    fn key(&self, path: &Path) -> Option<PathBuf> {
+        let root = self.project.clone().root();
+        normalize(&self.root.join(path.strip_prefix(&root).unwrap_or(path)))
+    }
+
+    /// Finds the document a wiki link points to, by its title first and then
+    /// by its slug.
+    fn find(&self, target: &str) -> Result<&Page, String> {
+        let by_title = self.titles.get(&target.to_lowercase());
+        let by_slug = self.slugs.get(&slugify(target));
+        match by_title.or(by_slug).map(Vec::as_slice) {
+            Some([key]) => Ok(&self.pages[key]),
+            Some(keys) => Err(format!(
+                "could be any of {:?}",
+                keys.iter()
+                    .map(|key| &self.pages[key].input)
+                    .collect::<Vec<&PathBuf>>()
+            )),
+            None => Err("matches no document".to_string()),
+        }
+    }
+
+    fn resolve(&self, page: &Page) -> Result<Links, PlanError> {
+        let mut links = Links::default();
+        let (_, body) = crate::front_matter::parse(&page.source);
+        let broken = |link: &str, reason: &str| PlanError::BrokenDocumentLink {
+            input: page.input.clone(),
+            link: link.to_string(),
+            reason: reason.to_string(),
+        };
+
+        if page.input.extension().is_some_and(|ext| ext == "md") {
+            let input = self
+                .key(&page.input)
+                .ok_or_else(|| PlanError::OutsideProject {
+                    input: page.input.clone(),
+                })?;
+            let (document_links, wiki_links) = doc_links::links(body, &page.opts);
+            for link in document_links {
+                let path = percent_decode(&link);
+                let target = match path.strip_prefix('/') {
+                    Some(path) => self.root.join(path),
+                    None => input.parent().unwrap_or(&self.root).join(&path),
+                };
+                let target =
+                    normalize(&target).ok_or_else(|| broken(&link, "is outside the project"))?;
+                let url = match self.pages.get(&target) {
+                    Some(target) => self.project.url_for(&target.output),
+                    None if target.exists() => return Err(broken(&link, "is not being built")),
+                    None => return Err(broken(&link, "does not exist")),
+                };
+                links.links.insert(link, url);
+            }
+
+            for link in wiki_links {
+                let target = self
+                    .find(&link.target)
+                    .map_err(|reason| broken(&format!("[[{}]]", link.target), &reason))?;
+                links
+                    .wiki_links
+                    .insert(link.target, self.project.url_for(&target.output));
+                links.dependencies.insert(target.input.clone());
+            }
+        }
+        Ok(links)
+    }
+}
+
+/// Finds the links every document has to other documents in the project,
+/// wherever they are, and the URLs they will be published at. Documents that
+/// wiki link to others depend on them, and every page gets the documents that
+/// link to it as backlinks. Feeds and search indexes compile documents too,
+/// so their entries get the same links.
+pub fn link_documents(project: &Project, plan: BuildPlan) -> Result<BuildPlan, PlanError> {
+    let index = Index::new(project, &plan)?;
+
+    let mut resolved: HashMap<PathBuf, Links> = HashMap::new();
+    let mut backlinks: HashMap<String, BTreeSet<Backlink>> = HashMap::new();
+    for page in index.pages.values() {
+        let links = index.resolve(page)?;
+        let url = project.url_for(&page.output);
+        for target in links.links.values().chain(links.wiki_links.values()) {
+            if *target != url {
+                backlinks
+                    .entry(target.clone())
+                    .or_default()
+                    .insert(Backlink {
+                        title: page.title.clone(),
+                        url: url.clone(),
+                    });
+            }
+        }
+        resolved.insert(page.input.clone(), links);
+    }
+
+    let backlinks_of = |output: &Path| -> Vec<Backlink> {
+        backlinks
+            .get(&project.url_for(output))
+            .map(|backlinks| backlinks.iter().cloned().collect())
+            .unwrap_or_default()
+    };
+
+    plan.try_map(|unit| match unit {
+        CompilationUnit::Compile {
+            input,
+            output,
+            banner,
+            markdown,
+            highlight,
+            toc,
+            ..
+        } => {
+            let links = resolved.get(&input).cloned().unwrap_or_default();
+            Ok(CompilationUnit::Compile {
+                input,
+                output,
+                banner,
+                markdown,
+                highlight,
+                toc,
+                links: links.links,
+                wiki_links: links.wiki_links,
+                dependencies: links.dependencies.into_iter().collect(),
+            })
+        }
+        CompilationUnit::Template {
+            input,
+            output,
+            template,
+            toc,
+            ..
+        } => Ok(CompilationUnit::Template {
+            backlinks: backlinks_of(&output),
+            input,
+            output,
+            template,
+            toc,
+        }),
+        CompilationUnit::Feed {
+            output,
+            format,
+            channel,
+            full_content,
+            markdown,
+            toc,
+            entries,
+        } => Ok(CompilationUnit::Feed {
+            output,
+            format,
+            channel,
+            full_content,
+            markdown,
+            toc,
+            entries: entries
+                .into_iter()
+                .map(|entry| {
+                    let links = resolved.get(&entry.input).cloned().unwrap_or_default();
+                    FeedEntry {
+                        links: links.links,
+                        wiki_links: links.wiki_links,
+                        ..entry
+                    }
+                })
+                .collect(),
+        }),
+        CompilationUnit::SearchIndex {
+            output,
+            documents,
+            markdown,
+        } => Ok(CompilationUnit::SearchIndex {
+            output,
+            documents: documents
+                .into_iter()
+                .map(|document| {
+                    let links = resolved.get(&document.input).cloned().unwrap_or_default();
+                    SearchDocument {
+                        links: links.links,
+                        wiki_links: links.wiki_links,
+                        ..document
+                    }
+                })
+                .collect(),
+            markdown,
+        }),
+        unit => Ok(unit),
+    })
+}
@@ -0,0 +1,632 @@
+use chrono::Datelike;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::build_rules::CompilationUnit;
+use crate::document::{slugify, Document};
+use crate::feed::{Channel, FeedEntry};
+use crate::model::{Archive, Collection, Feed, Markdown, Project, Search, SortBy, Taxonomy, Toc};
+use crate::search::SearchDocument;
+use crate::template::Value;
+
+/// Everything a listing template gets to know about a document.
+pub fn entry(project: &Project, doc: &Document, output: &Path) -> Value {
+    Value::map()
+        .with("title", doc.title())
+        .with("url", project.url_for(output))
+        .with(
+            "date",
+            doc.date().map(|date| date.format("%Y-%m-%d").to_string()),
+        )
+        .with("excerpt", Value::html(doc.excerpt()))
+        .with("slug", doc.slug())
+        .with("tags", doc.front_matter().list("tags"))
+        .with("categories", doc.front_matter().list("categories"))
+        .with("draft", doc.is_draft())
+}
+
+fn weight(doc: &Document) -> i64 {
+    doc.front_matter()
+        .get("weight")
+        .and_then(|weight| weight.trim().parse().ok())
+        .unwrap_or(i64::MAX)
+}
+
+fn compare(sort_by: SortBy, a: &Document, b: &Document) -> Ordering {
+    let by_title = || a.title().to_lowercase().cmp(&b.title().to_lowercase());
+    match sort_by {
+        // Undated documents go after dated ones, whichever the order.
+        SortBy::Date => match (a.date(), b.date()) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        },
+        SortBy::Title => by_title(),
+        SortBy::Weight => weight(a).cmp(&weight(b)),
+    }
+    .then_with(by_title)
+    .then_with(|| a.path().cmp(b.path()))
+}
+
+/// Sorts documents in the order they will be listed.
+pub fn sort<'a, I>(sort_by: SortBy, descending: bool, docs: I) -> Vec<&'a (Document, PathBuf)>
+where
+    I: Iterator<Item = &'a (Document, PathBuf)>,
+{
+    let mut sorted: Vec<&(Document, PathBuf)> = docs.collect();
+    sorted.sort_by(|(a, _), (b, _)| {
+        let undated = sort_by == SortBy::Date && (a.date().is_none() || b.date().is_none());
+        match compare(sort_by, a, b) {
+            ordering if descending && !undated => ordering.reverse(),
+            ordering => ordering,
+        }
+    });
+    sorted
+}
+
+/// Where the given page of a listing goes. The first page is the listing's
+/// own output, and the rest go in `page/N/index.html` next to it.
+fn page_output(output: &Path, number: usize) -> PathBuf {
+    if number == 1 {
+        return output.to_path_buf();
+    }
+    output
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join("page")
+        .join(number.to_string())
+        .join("index.html")
+}
+
+/// Plans the pages of a listing, splitting its entries in pages of `per_page`
+/// entries when given. Every page only depends on its own entries, so a
+/// change to one document only rebuilds the page that lists it.
+pub fn plan_pages(
+    project: &Project,
+    output: PathBuf,
+    template: Option<PathBuf>,
+    per_page: Option<usize>,
+    context: Value,
+    entries: Vec<(PathBuf, Value)>,
+) -> Vec<CompilationUnit> {
+    let count = entries.len();
+    let per_page = per_page.unwrap_or(count).max(1);
+    let total = count.div_ceil(per_page).max(1);
+    let url = |number: usize| project.url_for(&page_output(&output, number));
+
+    let pages: Vec<Value> = (1..=total)
+        .map(|number| Value::map().with("number", number).with("url", url(number)))
+        .collect();
+
+    (1..=total)
+        .map(|number| {
+            let page_entries: Vec<&(PathBuf, Value)> = entries
+                .iter()
+                .skip((number - 1) * per_page)
+                .take(per_page)
+                .collect();
+            let page = Value::map()
+                .with("number", number)
+                .with("total", total)
+                .with("prev", (number > 1).then(|| url(number - 1)))
+                .with("next", (number < total).then(|| url(number + 1)))
+                .with(
+                    "pages",
+                    pages
+                        .iter()
+                        .map(|p| {
+                            p.clone()
+                                .with("current", p.get("number") == Some(&number.into()))
+                        })
+                        .collect::<Vec<Value>>(),
+                );
+
+            CompilationUnit::Generate {
+                inputs: page_entries
+                    .iter()
+                    .map(|(input, _)| input.clone())
+                    .collect(),
+                output: page_output(&output, number),
+                template: template.clone(),
+                context: context
+                    .clone()
+                    .with("count", count)
+                    .with(
+                        "entries",
+                        page_entries
+                            .iter()
+                            .map(|(_, entry)| entry.clone())
+                            .collect::<Vec<Value>>(),
+                    )
+                    .with("page", page),
+            }
+        })
+        .collect()
+}
+
+/// Plans the index pages of a collection, listing all of the given documents.
+pub fn plan_collection(
+    project: &Project,
+    collection: &Collection,
+    root: &Path,
+    output_dir: &Path,
+    docs: &[(Document, PathBuf)],
+) -> Vec<CompilationUnit> {
+    let sort_by = collection.clone().sort_by();
+    let descending = collection.clone().descending();
+    let entries: Vec<(PathBuf, Value)> = sort(sort_by, descending, docs.iter())
+        .iter()
+        .map(|(doc, output)| (doc.input().to_path_buf(), entry(project, doc, output)))
+        .collect();
+
+    let title = collection.clone().title().unwrap_or_else(|| {
+        root.file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("Index")
+            .to_string()
+    });
+
+    plan_pages(
+        project,
+        output_dir.join(collection.clone().output()),
+        collection.clone().template().map(|t| root.join(t)),
+        collection.clone().paginate(),
+        Value::map().with("title", title),
+        entries,
+    )
+}
+
+/// Plans a page for every term of a taxonomy, listing the documents that
+/// have that term, and a page listing all the terms. Terms are told apart by
+/// their slug, so `Rust` and `rust` are the same term.
+pub fn plan_taxonomy(
+    project: &Project,
+    taxonomy: &Taxonomy,
+    root: &Path,
+    output_dir: &Path,
+    docs: &[(Document, PathBuf)],
+) -> Vec<CompilationUnit> {
+    let name = taxonomy.clone().name();
+    let taxonomy_dir = output_dir.join(slugify(&name));
+
+    // Every term is listed under the first spelling we find for it.
+    let mut names: BTreeMap<String, String> = BTreeMap::new();
+    let mut members: BTreeMap<String, Vec<&(Document, PathBuf)>> = BTreeMap::new();
+    for doc in docs {
+        for term in doc.0.front_matter().list(&name) {
+            let slug = slugify(&term);
+            if slug.is_empty() {
+                continue;
+            }
+            names.entry(slug.clone()).or_insert(term);
+            let listed = members.entry(slug).or_default();
+            if !listed
+                .iter()
+                .any(|member| member.0.input() == doc.0.input())
+            {
+                listed.push(doc);
+            }
+        }
+    }
+
+    let template = taxonomy.clone().template().map(|t| root.join(t));
+    let mut units = vec![];
+    let mut term_values = vec![];
+
+    for (slug, members) in members {
+        let term = &names[&slug];
+        let output = taxonomy_dir.join(&slug).join("index.html");
+        let entries: Vec<(PathBuf, Value)> = sort(SortBy::Date, true, members.into_iter())
+            .into_iter()
+            .map(|(doc, output)| (doc.input().to_path_buf(), entry(project, doc, output)))
+            .collect();
+
+        term_values.push(
+            Value::map()
+                .with("name", term.as_str())
+                .with("slug", slug.as_str())
+                .with("url", project.url_for(&output))
+                .with("count", entries.len()),
+        );
+
+        units.extend(plan_pages(
+            project,
+            output,
+            template.clone(),
+            taxonomy.clone().paginate(),
+            Value::map()
+                .with("title", term.as_str())
+                .with("taxonomy", name.as_str())
+                .with("term", term.as_str()),
+            entries,
+        ));
+    }
+
+    units.push(CompilationUnit::Generate {
+        inputs: vec![],
+        output: taxonomy_dir.join("index.html"),
+        template: taxonomy.clone().index_template().map(|t| root.join(t)),
+        context: Value::map()
+            .with("title", name.as_str())
+            .with("taxonomy", name.as_str())
+            .with("count", term_values.len())
+            .with("terms", term_values),
+    });
+
+    units
+}
+
+/// Plans a page for every year documents were published in, in
+/// `YEAR/index.html`, and when the archive is monthly a page for every month
+/// too, in `YEAR/MONTH/index.html`. Undated documents are left out.
+pub fn plan_archive(
+    project: &Project,
+    archive: &Archive,
+    root: &Path,
+    output_dir: &Path,
+    docs: &[(Document, PathBuf)],
+) -> Vec<CompilationUnit> {
+    let mut months: BTreeMap<(i32, u32), Vec<&(Document, PathBuf)>> = BTreeMap::new();
+    for doc in docs {
+        if let Some(date) = doc.0.date() {
+            months
+                .entry((date.year(), date.month()))
+                .or_default()
+                .push(doc);
+        }
+    }
+    let mut years: Vec<i32> = months.keys().map(|(year, _)| *year).collect();
+    years.dedup();
+
+    let year_output = |year: i32| output_dir.join(year.to_string()).join("index.html");
+    let month_output = |year: i32, month: u32| {
+        output_dir
+            .join(year.to_string())
+            .join(format!("{:02}", month))
+            .join("index.html")
+    };
+    let in_year = |year: i32| {
+        months
+            .iter()
+            .filter(move |((y, _), _)| *y == year)
+            .map(|((_, month), docs)| (*month, docs))
+    };
+    let entries = |docs: Vec<&(Document, PathBuf)>| -> Vec<(PathBuf, Value)> {
+        sort(SortBy::Date, true, docs.into_iter())
+            .into_iter()
+            .map(|(doc, output)| (doc.input().to_path_buf(), entry(project, doc, output)))
+            .collect()
+    };
+
+    let year_values: Vec<Value> = years
+        .iter()
+        .rev()
+        .map(|year| {
+            Value::map()
+                .with("year", year.to_string())
+                .with("url", project.url_for(&year_output(*year)))
+                .with(
+                    "count",
+                    in_year(*year).map(|(_, docs)| docs.len()).sum::<usize>(),
+                )
+        })
+        .collect();
+
+    let template = archive.clone().template().map(|t| root.join(t));
+    let per_page = archive.clone().paginate();
+    let monthly = archive.clone().monthly();
+    let mut units = vec![];
+
+    for year in years {
+        let month_values: Vec<Value> = in_year(year)
+            .filter(|_| monthly)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .map(|(month, docs)| {
+                Value::map()
+                    .with("month", format!("{:02}", month))
+                    .with("name", month_name(month))
+                    .with("url", project.url_for(&month_output(year, month)))
+                    .with("count", docs.len())
+            })
+            .collect();
+        let context = Value::map()
+            .with("year", year.to_string())
+            .with("years", year_values.clone());
+
+        units.extend(plan_pages(
+            project,
+            year_output(year),
+            template.clone(),
+            per_page,
+            context
+                .clone()
+                .with("title", year.to_string())
+                .with("months", month_values),
+            entries(in_year(year).flat_map(|(_, docs)| docs.clone()).collect()),
+        ));
+
+        if !monthly {
+            continue;
+        }
+        for (month, docs) in in_year(year) {
+            units.extend(plan_pages(
+                project,
+                month_output(year, month),
+                template.clone(),
+                per_page,
+                context
+                    .clone()
+                    .with("title", format!("{} {}", month_name(month), year))
+                    .with("month", format!("{:02}", month)),
+                entries(docs.clone()),
+            ));
+        }
+    }
+
+    units
+}
+
+/// Plans the feeds of a site, with its latest documents.
+pub fn plan_feed(
+    project: &Project,
+    feed: &Feed,
+    markdown: &Markdown,
+    toc: &Toc,
+    root: &Path,
+    output_dir: &Path,
+    docs: &[(Document, PathBuf)],
+) -> Vec<CompilationUnit> {
+    let absolute_url = |output: &Path| {
+        project
+            .absolute_url_for(output)
+            .unwrap_or_else(|| project.url_for(output))
+    };
+    let entries: Vec<FeedEntry> = sort(SortBy::Date, true, docs.iter())
+        .into_iter()
+        .take(feed.clone().limit())
+        .map(|(doc, output)| FeedEntry {
+            input: doc.input().to_path_buf(),
+            title: doc.title(),
+            url: absolute_url(output),
+            date: doc.date(),
+            summary: doc.excerpt(),
+            links: BTreeMap::new(),
+            wiki_links: BTreeMap::new(),
+        })
+        .collect();
+    let title = feed.clone().title().unwrap_or_else(|| {
+        root.file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("Feed")
+            .to_string()
+    });
+
+    feed.clone()
+        .outputs()
+        .into_iter()
+        .map(|(format, output)| {
+            let output = output_dir.join(output);
+            CompilationUnit::Feed {
+                format,
+                channel: Channel {
+                    title: title.clone(),
+                    link: absolute_url(&output_dir.join("index.html")),
+                    url: absolute_url(&output),
+                },
+                output,
+                full_content: feed.clone().full_content(),
+                markdown: markdown.clone(),
+                toc: toc.clone(),
+                entries: entries.clone(),
+            }
+        })
+        .collect()
+}
+
+/// Plans a JSON listing of every document in a site, for other programs to
+/// find what's in it without going through its pages.
+pub fn plan_content_index(
+    project: &Project,
+    output: PathBuf,
+    docs: &[(Document, PathBuf)],
+) -> CompilationUnit {
+    let documents: Vec<Value> = sort(SortBy::Date, true, docs.iter())
+        .into_iter()
+        .map(|(doc, output)| {
+            let document = Value::map()
+                .with(
+                    "url",
+                    project
+                        .absolute_url_for(output)
+                        .unwrap_or_else(|| project.url_for(output)),
+                )
+                .with("title", doc.title())
+                .with("tags", doc.front_matter().list("tags"))
+                .with("word_count", doc.word_count());
+            match doc.date() {
+                Some(date) => document.with("date", date.format("%Y-%m-%d").to_string()),
+                None => document,
+            }
+        })
+        .collect();
+
+    CompilationUnit::Json {
+        inputs: docs
+            .iter()
+            .map(|(doc, _)| doc.input().to_path_buf())
+            .collect(),
+        output,
+        data: Value::map().with("documents", documents),
+    }
+}
+
+/// Plans the search index of a site, and the script to search it with.
+pub fn plan_search(
+    project: &Project,
+    search: &Search,
+    markdown: &Markdown,
+    output_dir: &Path,
+    docs: &[(Document, PathBuf)],
+) -> Vec<CompilationUnit> {
+    let documents = docs
+        .iter()
+        .map(|(doc, output)| SearchDocument {
+            input: doc.input().to_path_buf(),
+            title: doc.title(),
+            url: project.url_for(output),
+            links: BTreeMap::new(),
+            wiki_links: BTreeMap::new(),
+        })
+        .collect();
+
+    vec![
+        CompilationUnit::SearchIndex {
+            output: output_dir.join(search.clone().index()),
+            documents,
+            markdown: markdown.clone(),
+        },
+        CompilationUnit::SearchScript {
+            output: output_dir.join(search.clone().script()),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        },
+    ]
+}
+
+fn month_name(month: u32) -> String {
+    chrono::NaiveDate::from_ymd_opt(2000, month, 1)
+        .map(|date| date.format("%B").to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::SExpr;
+
+    #[test]
+    fn test_plan_pages() {
+        let project = Project::new();
+        let entries: Vec<(PathBuf, Value)> = (1..=25)
+            .map(|n| (PathBuf::from(format!("{}.md", n)), Value::map()))
+            .collect();
+        let pages = plan_pages(
+            &project,
+            PathBuf::from("./_public/blog/index.html"),
+            None,
+            Some(10),
+            Value::map(),
+            entries,
+        );
+
+        let outputs: Vec<PathBuf> = pages.iter().filter_map(CompilationUnit::output).collect();
+        assert_eq!(
+            outputs,
+            vec![
+                PathBuf::from("./_public/blog/index.html"),
+                PathBuf::from("./_public/blog/page/2/index.html"),
+                PathBuf::from("./_public/blog/page/3/index.html"),
+            ]
+        );
+
+        match &pages[1] {
+            CompilationUnit::Generate {
+                inputs, context, ..
+            } => {
+                assert_eq!(inputs.first(), Some(&PathBuf::from("11.md")));
+                assert_eq!(inputs.len(), 10);
+                let page = context.get("page").unwrap();
+                assert_eq!(page.get("prev"), Some(&Value::from("/blog/")));
+                assert_eq!(page.get("next"), Some(&Value::from("/blog/page/3/")));
+            }
+            unit => panic!("Expected a Generate unit, got {:?}", unit),
+        }
+    }
+
+    #[test]
+    fn test_plan_taxonomy() {
+        let project = Project::new();
+        let doc = |name: &str, tags: &str| {
+            let doc = Document::from_source(
+                PathBuf::from(name),
+                PathBuf::from(name),
+                &format!("---\ntags: {}\n---\n# {}\n", tags, name),
+            );
+            (doc, PathBuf::from(format!("./_public/{}.html", name)))
+        };
+        let docs = vec![doc("a", "[Rust, web]"), doc("b", "[rust]")];
+        let taxonomy = Taxonomy::from_sexp(&SExpr::Atom("tags".to_string()), &[]);
+        let units = plan_taxonomy(
+            &project,
+            &taxonomy,
+            Path::new("."),
+            Path::new("./_public"),
+            &docs,
+        );
+
+        let outputs: Vec<PathBuf> = units.iter().filter_map(CompilationUnit::output).collect();
+        assert_eq!(
+            outputs,
+            vec![
+                PathBuf::from("./_public/tags/rust/index.html"),
+                PathBuf::from("./_public/tags/web/index.html"),
+                PathBuf::from("./_public/tags/index.html"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_archive() {
+        let project = Project::new();
+        let docs: Vec<(Document, PathBuf)> = [
+            "2019-03-01-a.md",
+            "2019-03-09-b.md",
+            "2020-01-01-c.md",
+            "d.md",
+        ]
+        .iter()
+        .map(|name| {
+            let doc = Document::from_source(PathBuf::from(name), PathBuf::from(name), "");
+            (doc, PathBuf::from("./_public").join(name))
+        })
+        .collect();
+        let archive = Archive::from_sexp(&[SExpr::List(vec![SExpr::Atom("monthly".to_string())])]);
+        let units = plan_archive(
+            &project,
+            &archive,
+            Path::new("."),
+            Path::new("./_public"),
+            &docs,
+        );
+
+        let outputs: Vec<PathBuf> = units.iter().filter_map(CompilationUnit::output).collect();
+        assert_eq!(
+            outputs,
+            vec![
+                PathBuf::from("./_public/2019/index.html"),
+                PathBuf::from("./_public/2019/03/index.html"),
+                PathBuf::from("./_public/2020/index.html"),
+                PathBuf::from("./_public/2020/01/index.html"),
+            ]
+        );
+
+        match &units[1] {
+            CompilationUnit::Generate {
+                inputs, context, ..
+            } => {
+                assert_eq!(
+                    inputs,
+                    &vec![
+                        PathBuf::from("2019-03-09-b.md"),
+                        PathBuf::from("2019-03-01-a.md")
+                    ]
+                );
+                assert_eq!(context.get("title"), Some(&Value::from("March 2019")));
+            }
+            unit => panic!("Expected a Generate unit, got {:?}", unit),
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::build_rules::{Artifact, CompilationUnit};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<ReportFormat, String> {
+        match format {
+            "text" => Ok(ReportFormat::Text),
+            "json" => Ok(ReportFormat::Json),
+            _ => Err(format!(
+                "Unknown report format {:?}, try one of: text, json",
+                format
+            )),
+        }
+    }
+}
+
+/// What happened to a unit in a build.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Built,
+    CacheHit,
+    Failed,
+    /// Something it depends on failed, so it was never run.
+    Blocked,
+}
+
+impl Status {
+    pub fn name(self) -> &'static str {
+        match self {
+            Status::Built => "built",
+            Status::CacheHit => "cache-hit",
+            Status::Failed => "failed",
+            Status::Blocked => "blocked",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UnitReport {
+    pub unit: CompilationUnit,
+    pub status: Status,
+    pub duration: Duration,
+    /// How big the output is after the build, for units that write a file.
+    pub size: Option<u64>,
+    pub error: Option<String>,
+    pub artifact: Option<Artifact>,
+}
+
+impl UnitReport {
+    pub fn to_json(&self) -> serde_json::Value {
+        let unit = match &self.unit {
+            CompilationUnit::CacheHit { unit } => unit,
+            unit => unit,
+        };
+        serde_json::json!({
+            "rule": unit.rule(),
+            "inputs": unit.inputs(),
+            "output": unit.path(),
+            "status": self.status.name(),
+            "duration_ms": millis(self.duration),
+            "size": self.size,
+            "error": self.error,
+        })
+    }
+}
+
+/// Every unit in a build and what happened to it, in the order they ran in.
+#[derive(Debug, Clone)]
+pub struct BuildReport {
+    units: Vec<UnitReport>,
+    duration: Duration,
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_micros() as f64 / 1000.0
+}
+
+impl BuildReport {
+    pub fn new(units: Vec<UnitReport>, duration: Duration) -> BuildReport {
+        BuildReport { units, duration }
+    }
+
+    pub fn units(self) -> Vec<UnitReport> {
+        self.units
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    pub fn count(&self, status: Status) -> usize {
+        self.units
+            .iter()
+            .filter(|unit| unit.status == status)
+            .count()
+    }
+
+    /// What the units that ran produced.
+    pub fn artifacts(self) -> Vec<Artifact> {
+        self.units
+            .into_iter()
+            .filter_map(|unit| unit.artifact)
+            .collect()
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let statuses = [
+            Status::Built,
+            Status::CacheHit,
+            Status::Failed,
+            Status::Blocked,
+        ];
+        let counts: serde_json::Map<String, serde_json::Value> = statuses
+            .iter()
+            .map(|status| (status.name().to_string(), self.count(*status).into()))
+            .collect();
+        serde_json::json!({
+            "duration_ms": millis(self.duration),
+            "counts": counts,
+            "units": self.units.iter().map(UnitReport::to_json).collect::<Vec<serde_json::Value>>(),
+        })
+    }
+}
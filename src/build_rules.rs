@@ -1,8 +1,25 @@
 use nipper::Document;
+use std::collections::BTreeMap;
 use std::ffi::OsStr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Clone, PartialEq)]
+use crate::doc_links::{self, Backlink};
+use crate::feed::{self, Channel, FeedEntry};
+use crate::front_matter;
+use crate::highlight;
+use crate::math;
+use crate::model::{FeedFormat, Highlight, Markdown, Toc};
+use crate::search::{self, SearchDocument};
+use crate::sitemap::{self, SitemapUrl};
+use crate::template::{self, Value};
+use crate::toc;
+
+const DRAFT_BANNER_STYLE: &str = "background: #ffd54f; color: #000; padding: 0.5em 1em; \
+                                  font-family: sans-serif; text-align: center;";
+
+const DEFAULT_INDEX_TEMPLATE: &str = include_str!("default_index.html");
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum CompilationUnit {
     CreateDir {
         path: PathBuf,
@@ -20,15 +37,226 @@ pub enum CompilationUnit {
     Compile {
         input: PathBuf,
         output: PathBuf,
+        banner: Option<String>,
+        markdown: Markdown,
+        highlight: Option<Highlight>,
+        toc: Toc,
+        links: BTreeMap<String, String>,
+        wiki_links: BTreeMap<String, String>,
+        dependencies: Vec<PathBuf>,
     },
 
     Template {
         input: PathBuf,
         output: PathBuf,
         template: PathBuf,
+        toc: Toc,
+        backlinks: Vec<Backlink>,
+    },
+
+    Generate {
+        inputs: Vec<PathBuf>,
+        output: PathBuf,
+        template: Option<PathBuf>,
+        context: Value,
+    },
+
+    Feed {
+        output: PathBuf,
+        format: FeedFormat,
+        channel: Channel,
+        full_content: bool,
+        markdown: Markdown,
+        toc: Toc,
+        entries: Vec<FeedEntry>,
+    },
+
+    Json {
+        inputs: Vec<PathBuf>,
+        output: PathBuf,
+        data: Value,
+    },
+
+    Sitemap {
+        output: PathBuf,
+        urls: Vec<SitemapUrl>,
+    },
+
+    Robots {
+        output: PathBuf,
+        sitemap: Option<String>,
+    },
+
+    SearchIndex {
+        output: PathBuf,
+        documents: Vec<SearchDocument>,
+        markdown: Markdown,
+    },
+
+    SearchScript {
+        output: PathBuf,
+        version: String,
+    },
+
+    Delete {
+        path: PathBuf,
     },
 }
 
+impl CompilationUnit {
+    /// The file this unit writes to, if it writes to any.
+    pub fn output(&self) -> Option<PathBuf> {
+        match self {
+            CompilationUnit::CreateDir { .. } => None,
+            CompilationUnit::CacheHit { unit } => unit.output(),
+            CompilationUnit::Copy { output, .. } => Some(output.clone()),
+            CompilationUnit::Compile { output, .. } => Some(output.clone()),
+            CompilationUnit::Template { output, .. } => Some(output.clone()),
+            CompilationUnit::Generate { output, .. } => Some(output.clone()),
+            CompilationUnit::Feed { output, .. } => Some(output.clone()),
+            CompilationUnit::Json { output, .. } => Some(output.clone()),
+            CompilationUnit::Sitemap { output, .. } => Some(output.clone()),
+            CompilationUnit::Robots { output, .. } => Some(output.clone()),
+            CompilationUnit::SearchIndex { output, .. } => Some(output.clone()),
+            CompilationUnit::SearchScript { output, .. } => Some(output.clone()),
+            CompilationUnit::Delete { .. } => None,
+        }
+    }
+
+    /// The name of the rule this unit runs.
+    pub fn rule(&self) -> &'static str {
+        match self {
+            CompilationUnit::CreateDir { .. } => "create-dir",
+            CompilationUnit::CacheHit { unit } => unit.rule(),
+            CompilationUnit::Copy { .. } => "copy",
+            CompilationUnit::Compile { .. } => "compile",
+            CompilationUnit::Template { .. } => "template",
+            CompilationUnit::Generate { .. } => "generate",
+            CompilationUnit::Feed { .. } => "feed",
+            CompilationUnit::Json { .. } => "json",
+            CompilationUnit::Sitemap { .. } => "sitemap",
+            CompilationUnit::Robots { .. } => "robots",
+            CompilationUnit::SearchIndex { .. } => "search-index",
+            CompilationUnit::SearchScript { .. } => "search-script",
+            CompilationUnit::Delete { .. } => "delete",
+        }
+    }
+
+    /// The file or folder this unit works on, which tells units apart.
+    pub fn path(&self) -> PathBuf {
+        match self {
+            CompilationUnit::CacheHit { unit } => unit.path(),
+            CompilationUnit::CreateDir { path } | CompilationUnit::Delete { path } => path.clone(),
+            cunit => cunit.output().unwrap_or_default(),
+        }
+    }
+
+    /// The files this unit reads from.
+    pub fn inputs(&self) -> Vec<PathBuf> {
+        match self {
+            CompilationUnit::CacheHit { unit } => unit.inputs(),
+            CompilationUnit::Copy { input, .. } => vec![input.clone()],
+            CompilationUnit::Compile { input, .. } => vec![input.clone()],
+            CompilationUnit::Template {
+                input, template, ..
+            } => vec![input.clone(), template.clone()],
+            CompilationUnit::Generate {
+                inputs, template, ..
+            } => inputs.iter().chain(template.iter()).cloned().collect(),
+            CompilationUnit::Feed { entries, .. } => {
+                entries.iter().map(|entry| entry.input.clone()).collect()
+            }
+            CompilationUnit::Json { inputs, .. } => inputs.clone(),
+            CompilationUnit::SearchIndex { documents, .. } => documents
+                .iter()
+                .map(|document| document.input.clone())
+                .collect(),
+            CompilationUnit::CreateDir { .. }
+            | CompilationUnit::Sitemap { .. }
+            | CompilationUnit::Robots { .. }
+            | CompilationUnit::SearchScript { .. }
+            | CompilationUnit::Delete { .. } => vec![],
+        }
+    }
+}
+
+/// Compiles a document into HTML, leaving out its front matter, and
+/// highlighting its code blocks when asked to. A `[[toc]]` marker becomes the
+/// table of contents, or goes away without one.
+fn compile_document(
+    input: &Path,
+    markdown: &Markdown,
+    highlight: Option<&Highlight>,
+    toc: Option<&Toc>,
+    links: &BTreeMap<String, String>,
+    wiki_links: &BTreeMap<String, String>,
+) -> std::io::Result<String> {
+    let source = std::fs::read_to_string(input)?;
+    let (_, raw) = front_matter::parse(&source);
+    let front_matter_lines = source[..source.len() - raw.len()].matches('\n').count();
+    let ext = input.extension().and_then(OsStr::to_str).unwrap_or("");
+    let opts = markdown.comrak_options();
+    if ext != "md" {
+        return Ok(raw.to_string());
+    }
+
+    let (raw, formulas) = match markdown.math() {
+        true => math::extract(raw),
+        false => (raw.to_string(), vec![]),
+    };
+
+    let arena = comrak::Arena::new();
+    let root = comrak::parse_document(&arena, &raw, &opts);
+    let formulas = formulas
+        .iter()
+        .zip(math::formulas_in_text(root, &formulas))
+        .map(|(formula, in_text)| match in_text {
+            true => render_formula(input, front_matter_lines, formula),
+            false => Ok("".to_string()),
+        })
+        .collect::<std::io::Result<Vec<String>>>()?;
+    doc_links::rewrite_links(root, links);
+    doc_links::rewrite_wiki_links(&arena, root, wiki_links);
+    if let Some(highlight) = highlight {
+        highlight::highlight_code_blocks(root, highlight);
+    }
+    let mut html = vec![];
+    comrak::format_html(root, &opts, &mut html)?;
+    let html = String::from_utf8_lossy(&html);
+    let html = math::replace_placeholders(&html, &formulas);
+    Ok(match toc {
+        Some(toc) => toc::replace_marker(&html, toc),
+        None => toc::remove_marker(&html),
+    })
+}
+
+fn render_formula(
+    input: &Path,
+    front_matter_lines: usize,
+    formula: &math::Formula,
+) -> std::io::Result<String> {
+    math::render(&formula.tex, formula.display).map_err(|err| {
+        let line = front_matter_lines
+            + formula.line
+            + formula.tex[..err.offset.min(formula.tex.len())]
+                .matches('\n')
+                .count();
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{}:{}: {}", input.display(), line, err),
+        )
+    })
+}
+
+fn render_template(path: &Path, source: &str, context: &Value) -> std::io::Result<String> {
+    template::render(source, context).map_err(|err| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{:?}: {}", path, err),
+        )
+    })
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Artifact {
     File(PathBuf),
@@ -48,18 +276,31 @@ pub fn compile_unit(cunit: CompilationUnit) -> Result<Artifact, impl std::error:
             std::fs::copy(input, output.clone()).map(|_| Artifact::File(output))
         }
 
-        CompilationUnit::Compile { input, output } => {
-            let raw = std::fs::read_to_string(input.clone())?;
-            let ext = input.extension().and_then(OsStr::to_str).unwrap_or("");
-            let mut opts = comrak::ComrakOptions::default();
-            opts.render.unsafe_ = true;
-            opts.parse.smart = true;
-            opts.extension.autolink = true;
-            opts.extension.header_ids = Some("".to_string());
-            opts.extension.footnotes = true;
-            let compiled = match ext {
-                "md" => comrak::markdown_to_html(&raw, &opts),
-                _ => raw,
+        CompilationUnit::Compile {
+            input,
+            output,
+            banner,
+            markdown,
+            highlight,
+            toc,
+            links,
+            wiki_links,
+            ..
+        } => {
+            let compiled = compile_document(
+                &input,
+                &markdown,
+                highlight.as_ref(),
+                Some(&toc),
+                &links,
+                &wiki_links,
+            )?;
+            let compiled = match banner {
+                Some(banner) => format!(
+                    "<div class=\"hotstuff-draft-banner\" style=\"{}\">{}</div>\n{}",
+                    DRAFT_BANNER_STYLE, banner, compiled
+                ),
+                None => compiled,
             };
             std::fs::write(output.clone(), compiled).map(|_| Artifact::File(output))
         }
@@ -68,15 +309,201 @@ pub fn compile_unit(cunit: CompilationUnit) -> Result<Artifact, impl std::error:
             input,
             output,
             template,
+            toc,
+            backlinks,
         } => {
             let raw = std::fs::read_to_string(input)?;
             let html = Document::from(&raw);
             let title = html.select("h1").text();
-            let template = std::fs::read_to_string(template)?;
-            let compiled = template
-                .replace("{| title |}", &title)
-                .replace("{| document |}", &raw);
+            let source = std::fs::read_to_string(&template)?;
+            let context = Value::map()
+                .with("title", title.to_string())
+                .with("document", Value::html(raw.as_str()))
+                .with("toc", toc::to_value(&raw, &toc))
+                .with("toc_html", Value::html(toc::to_html(&raw, &toc)))
+                .with(
+                    "backlinks",
+                    backlinks
+                        .iter()
+                        .map(|backlink| {
+                            Value::map()
+                                .with("title", backlink.title.as_str())
+                                .with("url", backlink.url.as_str())
+                        })
+                        .collect::<Vec<Value>>(),
+                );
+            let compiled = render_template(&template, &source, &context)?;
+            std::fs::write(output.clone(), compiled).map(|_| Artifact::File(output))
+        }
+
+        CompilationUnit::Generate {
+            output,
+            template,
+            context,
+            ..
+        } => {
+            let compiled = match template {
+                Some(template) => {
+                    let source = std::fs::read_to_string(&template)?;
+                    render_template(&template, &source, &context)?
+                }
+                None => render_template(
+                    Path::new("default index template"),
+                    DEFAULT_INDEX_TEMPLATE,
+                    &context,
+                )?,
+            };
+            std::fs::write(output.clone(), compiled).map(|_| Artifact::File(output))
+        }
+
+        CompilationUnit::Feed {
+            output,
+            format,
+            channel,
+            full_content,
+            markdown,
+            toc,
+            entries,
+        } => {
+            let entries = entries
+                .iter()
+                .map(|entry| {
+                    let html = if full_content {
+                        compile_document(
+                            &entry.input,
+                            &markdown,
+                            None,
+                            Some(&toc),
+                            &entry.links,
+                            &entry.wiki_links,
+                        )?
+                    } else {
+                        entry.summary.clone()
+                    };
+                    Ok((entry, html))
+                })
+                .collect::<std::io::Result<Vec<(&FeedEntry, String)>>>()?;
+            let updated = entries
+                .iter()
+                .filter_map(|(entry, _)| entry.date)
+                .max()
+                .unwrap_or_else(|| chrono::Utc::now().naive_utc());
+            let compiled = feed::render(format, &channel, &entries, updated);
+            std::fs::write(output.clone(), compiled).map(|_| Artifact::File(output))
+        }
+
+        CompilationUnit::Json { output, data, .. } => {
+            let compiled = serde_json::to_string_pretty(&data.to_json())?;
             std::fs::write(output.clone(), compiled).map(|_| Artifact::File(output))
         }
+
+        CompilationUnit::Sitemap { output, urls } => {
+            let compiled = sitemap::render_sitemap(&urls);
+            std::fs::write(output.clone(), compiled).map(|_| Artifact::File(output))
+        }
+
+        CompilationUnit::Robots { output, sitemap } => {
+            let compiled = sitemap::render_robots(sitemap.as_deref());
+            std::fs::write(output.clone(), compiled).map(|_| Artifact::File(output))
+        }
+
+        CompilationUnit::SearchIndex {
+            output,
+            documents,
+            markdown,
+        } => {
+            let documents = documents
+                .iter()
+                .map(|document| {
+                    Ok((
+                        document,
+                        search::text_of(&compile_document(
+                            &document.input,
+                            &markdown,
+                            None,
+                            None,
+                            &document.links,
+                            &document.wiki_links,
+                        )?),
+                    ))
+                })
+                .collect::<std::io::Result<Vec<(&SearchDocument, String)>>>()?;
+            let compiled = serde_json::to_string(&search::build_index(&documents))?;
+            std::fs::write(output.clone(), compiled).map(|_| Artifact::File(output))
+        }
+
+        CompilationUnit::SearchScript { output, .. } => {
+            std::fs::write(output.clone(), search::SCRIPT).map(|_| Artifact::File(output))
+        }
+
+        CompilationUnit::Delete { path } => match std::fs::remove_file(&path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+            _ => Ok(Artifact::File(path)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::SExpr;
+
+    #[test]
+    fn test_toc_marker_in_feeds_and_search() {
+        let root = std::env::temp_dir().join(format!("hotstuff-rules-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let input = root.join("a.md");
+        std::fs::write(&input, "[[toc]]\n\n## Section One\n\nSee s.\n").unwrap();
+        let markdown =
+            Markdown::from_sexp(&[SExpr::List(vec![SExpr::Atom("header-ids".to_string())])]);
+        let toc = Toc::from_sexp(&[]);
+        let links = BTreeMap::new();
+
+        let entry = FeedEntry {
+            input: input.clone(),
+            title: "A".to_string(),
+            url: "https://example.com/a.html".to_string(),
+            date: None,
+            summary: "".to_string(),
+            links: links.clone(),
+            wiki_links: links.clone(),
+        };
+        compile_unit(CompilationUnit::Feed {
+            output: root.join("rss.xml"),
+            format: FeedFormat::Rss,
+            channel: Channel {
+                title: "Site".to_string(),
+                link: "https://example.com/".to_string(),
+                url: "https://example.com/rss.xml".to_string(),
+            },
+            full_content: true,
+            markdown: markdown.clone(),
+            toc,
+            entries: vec![entry],
+        })
+        .unwrap();
+        let rss = std::fs::read_to_string(root.join("rss.xml")).unwrap();
+        assert!(!rss.contains("[[toc]]"));
+        assert!(rss.contains("&lt;nav class=&quot;toc&quot;&gt;"));
+
+        let document = SearchDocument {
+            input,
+            title: "A".to_string(),
+            url: "/a.html".to_string(),
+            links: links.clone(),
+            wiki_links: links,
+        };
+        compile_unit(CompilationUnit::SearchIndex {
+            output: root.join("search.json"),
+            documents: vec![document],
+            markdown,
+        })
+        .unwrap();
+        let index = std::fs::read_to_string(root.join("search.json")).unwrap();
+        let index: serde_json::Value = serde_json::from_str(&index).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert!(index["index"].get("toc").is_none());
+        assert!(!index.to_string().contains("[[toc]]"));
     }
 }
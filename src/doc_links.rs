@@ -0,0 +1,275 @@
+use comrak::arena_tree::Node;
+use comrak::nodes::{Ast, AstNode, NodeLink, NodeValue};
+use comrak::{Arena, ComrakOptions};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::toc;
+
+/// A `[[target]]` or `[[target|label]]` link to another document, found by
+/// its title or its slug.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WikiLink {
+    pub target: String,
+    pub label: String,
+}
+
+/// A document that links to another one.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Backlink {
+    pub title: String,
+    pub url: String,
+}
+
+/// The path a link points to, if it points to another Markdown document
+/// rather than to a page or another website.
+pub fn document_path(url: &str) -> Option<&str> {
+    let path = url.split(['#', '?']).next().unwrap_or("");
+    let scheme = path
+        .find(':')
+        .is_some_and(|colon| !path[..colon].contains('/'));
+    if scheme || path.starts_with("//") || !path.ends_with(".md") {
+        return None;
+    }
+    Some(path)
+}
+
+fn link_urls<'a>(root: &'a AstNode<'a>) -> impl Iterator<Item = &'a AstNode<'a>> {
+    root.descendants().filter(|node| {
+        matches!(
+            node.data.borrow().value,
+            NodeValue::Link(_) | NodeValue::Image(_)
+        )
+    })
+}
+
+/// Every path to another Markdown document that a document links to.
+fn document_links<'a>(root: &'a AstNode<'a>) -> Vec<String> {
+    let mut paths: Vec<String> = link_urls(root)
+        .filter_map(|node| match &node.data.borrow().value {
+            NodeValue::Link(link) | NodeValue::Image(link) => {
+                document_path(&String::from_utf8_lossy(&link.url)).map(str::to_string)
+            }
+            _ => None,
+        })
+        .collect();
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// Points the links to other documents at the URLs those documents are
+/// published at, keeping any `#anchor` they have.
+pub fn rewrite_links<'a>(root: &'a AstNode<'a>, urls: &BTreeMap<String, String>) {
+    if urls.is_empty() {
+        return;
+    }
+    for node in link_urls(root) {
+        if let NodeValue::Link(link) | NodeValue::Image(link) = &mut node.data.borrow_mut().value {
+            let url = String::from_utf8_lossy(&link.url).to_string();
+            let (path, anchor) = match url.find(['#', '?']) {
+                Some(at) => url.split_at(at),
+                None => (url.as_str(), ""),
+            };
+            if let Some(target) = urls.get(path) {
+                link.url = format!("{}{}", target, anchor).into_bytes();
+            }
+        }
+    }
+}
+
+enum Piece<'a> {
+    Text(&'a str),
+    Wiki(WikiLink),
+}
+
+/// Splits text into plain text and wiki links. The `[[toc]]` marker is left
+/// for the table of contents.
+fn split_wiki_links(text: &str) -> Vec<Piece<'_>> {
+    let mut pieces = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find("[[") {
+        let inner = &rest[start + 2..];
+        let link = inner
+            .find("]]")
+            .map(|end| &inner[..end])
+            .filter(|link| !link.trim().is_empty() && !link.contains(['[', ']', '\n']));
+        match link {
+            Some(link) if format!("[[{}]]", link) != toc::MARKER => {
+                let (target, label) = link.split_once('|').unwrap_or((link, link));
+                pieces.push(Piece::Text(&rest[..start]));
+                pieces.push(Piece::Wiki(WikiLink {
+                    target: target.trim().to_string(),
+                    label: label.trim().to_string(),
+                }));
+                rest = &inner[link.len() + 2..];
+            }
+            _ => {
+                pieces.push(Piece::Text(&rest[..start + 2]));
+                rest = inner;
+            }
+        }
+    }
+    pieces.push(Piece::Text(rest));
+    pieces
+}
+
+/// The runs of text next to each other in a document. The parser splits text
+/// around brackets, so wiki links can span several text nodes.
+fn text_runs<'a>(root: &'a AstNode<'a>) -> Vec<(Vec<&'a AstNode<'a>>, String)> {
+    let text_of = |node: &'a AstNode<'a>| match &node.data.borrow().value {
+        NodeValue::Text(text) => Some(String::from_utf8_lossy(text).to_string()),
+        _ => None,
+    };
+    let mut runs = vec![];
+    for node in root.descendants() {
+        let starts_run =
+            text_of(node).is_some() && node.previous_sibling().and_then(text_of).is_none();
+        if !starts_run {
+            continue;
+        }
+        let mut nodes = vec![];
+        let mut text = String::new();
+        let mut next = Some(node);
+        while let Some(node) = next {
+            match text_of(node) {
+                Some(part) => text.push_str(&part),
+                None => break,
+            }
+            nodes.push(node);
+            next = node.next_sibling();
+        }
+        runs.push((nodes, text));
+    }
+    runs
+}
+
+/// Every wiki link in a document.
+fn wiki_links<'a>(root: &'a AstNode<'a>) -> Vec<WikiLink> {
+    text_runs(root)
+        .into_iter()
+        .flat_map(|(_, text)| {
+            split_wiki_links(&text)
+                .into_iter()
+                .filter_map(|piece| match piece {
+                    Piece::Wiki(link) => Some(link),
+                    Piece::Text(_) => None,
+                })
+                .collect::<Vec<WikiLink>>()
+        })
+        .collect()
+}
+
+/// Every path to another Markdown document that a document links to, and
+/// every wiki link in it. Most documents link nowhere, so they are only
+/// parsed when they have something that could be a link.
+pub fn links(source: &str, opts: &ComrakOptions) -> (Vec<String>, Vec<WikiLink>) {
+    if !["](", "]:", "[["]
+        .iter()
+        .any(|syntax| source.contains(syntax))
+    {
+        return (vec![], vec![]);
+    }
+    let arena = Arena::new();
+    let root = comrak::parse_document(&arena, source, opts);
+    (document_links(root), wiki_links(root))
+}
+
+/// Turns the wiki links in a document into links to the URLs their targets
+/// are published at.
+pub fn rewrite_wiki_links<'a>(
+    arena: &'a Arena<AstNode<'a>>,
+    root: &'a AstNode<'a>,
+    urls: &BTreeMap<String, String>,
+) {
+    if urls.is_empty() {
+        return;
+    }
+    let new_node = |value: NodeValue| -> &'a AstNode<'a> {
+        arena.alloc(Node::new(RefCell::new(Ast::new(value))))
+    };
+    for (nodes, text) in text_runs(root) {
+        let pieces = split_wiki_links(&text);
+        if !pieces.iter().any(|piece| matches!(piece, Piece::Wiki(_))) {
+            continue;
+        }
+        for piece in pieces {
+            let node = match piece {
+                Piece::Text("") => continue,
+                Piece::Text(text) => new_node(NodeValue::Text(text.as_bytes().to_vec())),
+                Piece::Wiki(link) => match urls.get(&link.target) {
+                    Some(url) => {
+                        let node = new_node(NodeValue::Link(NodeLink {
+                            url: url.as_bytes().to_vec(),
+                            title: vec![],
+                        }));
+                        node.append(new_node(NodeValue::Text(link.label.into_bytes())));
+                        node
+                    }
+                    None => new_node(NodeValue::Text(format!("[[{}]]", link.target).into_bytes())),
+                },
+            };
+            nodes[0].insert_before(node);
+        }
+        for node in nodes {
+            node.detach();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_document_links() {
+        let source = "[next](./43.md) and [top](../index.md#top), not \
+                      [these](https://example.com/a.md) or [page](43.html)\n\n\
+                      ![diagram](img/a.md?raw)\n\n[ref]: other.md\n\n[ref]\n";
+        assert_eq!(
+            links(source, &ComrakOptions::default()).0,
+            vec!["../index.md", "./43.md", "img/a.md", "other.md"]
+        );
+        assert_eq!(
+            links(
+                "No [links] here, just <b>words</b>.\n",
+                &ComrakOptions::default()
+            ),
+            (vec![], vec![])
+        );
+    }
+
+    #[test]
+    fn test_wiki_links() {
+        let source = "See [[Page Name]] and [[page-name | the page]].\n\n\
+                      [[toc]] `[[code]]` [[]] [[a\nb]]\n";
+        let opts = ComrakOptions::default();
+        assert_eq!(
+            links(source, &opts).1,
+            vec![
+                WikiLink {
+                    target: "Page Name".to_string(),
+                    label: "Page Name".to_string(),
+                },
+                WikiLink {
+                    target: "page-name".to_string(),
+                    label: "the page".to_string(),
+                },
+            ]
+        );
+
+        let arena = Arena::new();
+        let root = comrak::parse_document(&arena, source, &opts);
+        let urls = [("Page Name", "/a.html"), ("page-name", "/a.html")]
+            .iter()
+            .map(|(target, url)| (target.to_string(), url.to_string()))
+            .collect();
+        rewrite_wiki_links(&arena, root, &urls);
+        let mut html = vec![];
+        comrak::format_html(root, &opts, &mut html).unwrap();
+        assert_eq!(
+            String::from_utf8(html).unwrap().lines().next(),
+            Some("<p>See <a href=\"/a.html\">Page Name</a> and <a href=\"/a.html\">the page</a>.</p>")
+        );
+    }
+}
@@ -0,0 +1,391 @@
+use chrono::{NaiveDate, NaiveDateTime};
+use nipper::Document as Html;
+use std::path::{Path, PathBuf};
+
+use crate::front_matter::{self, FrontMatter};
+
+pub const DRAFTS_DIR: &str = "_drafts";
+
+/// A document within a site, along with the metadata we can learn about it
+/// without compiling it: its front matter and its file name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Document {
+    input: PathBuf,
+    path: PathBuf,
+    front_matter: FrontMatter,
+    body: String,
+    in_drafts_dir: bool,
+}
+
+pub fn parse_date(date: &str) -> Option<NaiveDateTime> {
+    let date = date.trim();
+    if let Ok(datetime) = chrono::DateTime::parse_from_rfc3339(date) {
+        return Some(datetime.naive_local());
+    }
+    for format in &["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"] {
+        if let Ok(datetime) = NaiveDateTime::parse_from_str(date, format) {
+            return Some(datetime);
+        }
+    }
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .ok()
+        .map(|date| date.and_hms(0, 0, 0))
+}
+
+pub fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+/// Splits a `2019-03-01-hello-world` style file stem into its date and the
+/// rest of the name, which is empty for stems that are only a date.
+fn split_date_prefix(stem: &str) -> (Option<NaiveDateTime>, &str) {
+    if stem.len() >= 10 && stem.is_char_boundary(10) {
+        if let Some(date) = parse_date(&stem[..10]) {
+            return (Some(date), stem[10..].trim_start_matches('-'));
+        }
+    }
+    (None, stem)
+}
+
+impl Document {
+    /// Reads the document at `root/path`, where `path` is relative to the
+    /// root of its site. Documents within a `_drafts` folder are drafts, and
+    /// are placed as if the folder wasn't there.
+    pub fn from_path(root: &Path, path: PathBuf) -> Document {
+        let input = root.join(&path);
+        let raw = std::fs::read_to_string(&input).unwrap_or_default();
+        Document::from_source(input, path, &raw)
+    }
+
+    /// Builds a document out of its source, as if it was read from `input`.
+    pub fn from_source(input: PathBuf, path: PathBuf, source: &str) -> Document {
+        let (front_matter, body) = front_matter::parse(source);
+        let in_drafts_dir = path.iter().any(|part| part == DRAFTS_DIR);
+        let path = path.iter().filter(|part| *part != DRAFTS_DIR).collect();
+        Document {
+            input,
+            path,
+            front_matter,
+            body: body.to_string(),
+            in_drafts_dir,
+        }
+    }
+
+    pub fn input(&self) -> &Path {
+        &self.input
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn front_matter(&self) -> &FrontMatter {
+        &self.front_matter
+    }
+
+    fn is_markdown(&self) -> bool {
+        self.path.extension().is_some_and(|ext| ext == "md")
+    }
+
+    /// The title of the document, from its `title` field or its first
+    /// top-level heading, falling back to its file name.
+    pub fn title(&self) -> String {
+        if let Some(title) = self.front_matter.get("title") {
+            return title.to_string();
+        }
+        let heading = if self.is_markdown() {
+            self.body
+                .lines()
+                .find_map(|line| line.strip_prefix("# "))
+                .map(|title| title.trim().to_string())
+        } else {
+            Some(
+                Html::from(self.body.as_str())
+                    .select("h1")
+                    .first()
+                    .text()
+                    .trim()
+                    .to_string(),
+            )
+        };
+        heading
+            .filter(|title| !title.is_empty())
+            .unwrap_or_else(|| self.undated_stem().to_string())
+    }
+
+    /// A short HTML summary of the document, from its `excerpt` or `summary`
+    /// fields, or its first paragraph of text.
+    pub fn excerpt(&self) -> String {
+        if let Some(excerpt) = self
+            .front_matter
+            .get("excerpt")
+            .or_else(|| self.front_matter.get("summary"))
+        {
+            return excerpt.to_string();
+        }
+        if !self.is_markdown() {
+            return Html::from(self.body.as_str())
+                .select("p")
+                .first()
+                .html()
+                .to_string();
+        }
+        let paragraph = self
+            .body
+            .split("\n\n")
+            .map(str::trim)
+            .find(|block| {
+                !block.is_empty()
+                    && !["#", ">", "<", "```", "|", "!", "-", "*", "=", "["]
+                        .iter()
+                        .any(|prefix| block.starts_with(prefix))
+            })
+            .unwrap_or("");
+        comrak::markdown_to_html(paragraph, &comrak::ComrakOptions::default())
+            .trim()
+            .to_string()
+    }
+
+    /// How many words the document has, leaving out its markup.
+    pub fn word_count(&self) -> usize {
+        let html = if self.is_markdown() {
+            comrak::markdown_to_html(&self.body, &comrak::ComrakOptions::default())
+        } else {
+            self.body.clone()
+        };
+        Html::from(html.as_str())
+            .select("body")
+            .text()
+            .split_whitespace()
+            .count()
+    }
+
+    fn stem(&self) -> &str {
+        self.path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or("")
+    }
+
+    /// The file stem without its date prefix, unless the date is all there
+    /// is to it.
+    fn undated_stem(&self) -> &str {
+        match split_date_prefix(self.stem()).1 {
+            "" => self.stem(),
+            rest => rest,
+        }
+    }
+
+    /// The date of the document, from its `date` field or from a date prefix
+    /// in its file name.
+    pub fn date(&self) -> Option<NaiveDateTime> {
+        self.front_matter
+            .get("date")
+            .and_then(parse_date)
+            .or_else(|| split_date_prefix(self.stem()).0)
+    }
+
+    pub fn is_draft(&self) -> bool {
+        self.in_drafts_dir || self.front_matter.flag("draft")
+    }
+
+    /// Whether the document is scheduled to be published after `now`.
+    pub fn is_future(&self, now: NaiveDateTime) -> bool {
+        self.date().is_some_and(|date| date > now)
+    }
+
+    pub fn slug(&self) -> String {
+        match self.front_matter.get("slug") {
+            Some(slug) => slugify(slug),
+            None => slugify(self.undated_stem()),
+        }
+    }
+
+    /// Looks up a field for a permalink, from the front matter first and then
+    /// from the fields we derive from the file name.
+    fn permalink_field(&self, name: &str) -> Result<String, String> {
+        let date = || {
+            self.date()
+                .ok_or_else(|| format!("`:{}` needs the document to have a date", name))
+        };
+        match name {
+            "year" => date().map(|d| d.format("%Y").to_string()),
+            "month" => date().map(|d| d.format("%m").to_string()),
+            "day" => date().map(|d| d.format("%d").to_string()),
+            "slug" => Ok(self.slug()),
+            "name" => Ok(self.stem().to_string()),
+            "path" => Ok(self
+                .path
+                .parent()
+                .and_then(|p| p.to_str())
+                .unwrap_or("")
+                .to_string()),
+            field => self
+                .front_matter
+                .get(field)
+                .map(slugify)
+                .ok_or_else(|| format!("`:{}` is not a field of this document", field)),
+        }
+    }
+
+    /// The output path of this document, relative to the output folder of its
+    /// site. Without a permalink the output mirrors the input path, and with
+    /// one its `:field`s get expanded. Permalinks ending in `/` produce
+    /// `index.html` files for pretty URLs.
+    pub fn output_path(&self, permalink: Option<&str>) -> Result<PathBuf, String> {
+        let permalink = match permalink {
+            Some(permalink) => permalink,
+            None => return Ok(self.path.with_extension("html")),
+        };
+
+        let mut output = PathBuf::new();
+        for segment in permalink.split('/') {
+            let mut expanded = String::new();
+            let mut rest = segment;
+            while let Some(start) = rest.find(':') {
+                expanded.push_str(&rest[..start]);
+                let field = &rest[start + 1..];
+                let end = field
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .unwrap_or(field.len());
+                if end == 0 {
+                    expanded.push(':');
+                } else {
+                    expanded.push_str(&self.permalink_field(&field[..end])?);
+                }
+                rest = &field[end..];
+            }
+            expanded.push_str(rest);
+            if !expanded.is_empty() {
+                output.push(expanded);
+            }
+        }
+
+        if permalink.ends_with('/') || output.as_os_str().is_empty() {
+            Ok(output.join("index.html"))
+        } else if output.extension().is_none() {
+            Ok(output.with_extension("html"))
+        } else {
+            Ok(output)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(path: &str, source: &str) -> Document {
+        let (front_matter, body) = front_matter::parse(source);
+        Document {
+            input: PathBuf::from(path),
+            path: PathBuf::from(path),
+            front_matter,
+            body: body.to_string(),
+            in_drafts_dir: false,
+        }
+    }
+
+    #[test]
+    fn test_title() {
+        assert_eq!(
+            document("a.md", "---\ntitle: Front\n---\n# Body\n").title(),
+            "Front"
+        );
+        assert_eq!(document("a.md", "Intro\n\n# Body\n").title(), "Body");
+        assert_eq!(document("a.html", "<h1>Markup</h1>").title(), "Markup");
+        assert_eq!(
+            document("2020-01-01-file-name.md", "No headings").title(),
+            "file-name"
+        );
+    }
+
+    #[test]
+    fn test_excerpt() {
+        let doc = document(
+            "a.md",
+            "# Title\n\n> Quote\n\nFirst *paragraph*\nof text.\n\nSecond.\n",
+        );
+        assert_eq!(doc.excerpt(), "<p>First <em>paragraph</em>\nof text.</p>");
+        let doc = document("a.md", "---\nsummary: In short\n---\nFirst.\n");
+        assert_eq!(doc.excerpt(), "In short");
+    }
+
+    #[test]
+    fn test_drafts() {
+        let doc = Document::from_path(Path::new("/nonexistent"), "_drafts/hello.md".into());
+        assert!(doc.is_draft());
+        assert_eq!(doc.output_path(None), Ok(PathBuf::from("hello.html")));
+        assert!(document("hello.md", "---\ndraft: true\n---\n").is_draft());
+        assert!(!document("hello.md", "---\ndraft: false\n---\n").is_draft());
+    }
+
+    #[test]
+    fn test_future() {
+        let now = parse_date("2020-06-01").unwrap();
+        assert!(document("2020-06-02-hello.md", "").is_future(now));
+        assert!(!document("2020-05-31-hello.md", "").is_future(now));
+        assert!(!document("hello.md", "").is_future(now));
+    }
+
+    #[test]
+    fn test_output_path_without_permalink() {
+        let doc = document("posts/hello.md", "");
+        assert_eq!(doc.output_path(None), Ok(PathBuf::from("posts/hello.html")));
+    }
+
+    #[test]
+    fn test_output_path_from_file_name() {
+        let doc = document("2019-03-01-Hello-World.md", "");
+        assert_eq!(
+            doc.output_path(Some("/:year/:month/:slug/")),
+            Ok(PathBuf::from("2019/03/hello-world/index.html"))
+        );
+        assert_eq!(
+            doc.output_path(Some("/:year/:day-:name")),
+            Ok(PathBuf::from("2019/01-2019-03-01-Hello-World.html"))
+        );
+    }
+
+    #[test]
+    fn test_date_only_file_name() {
+        let doc = document("2019-03-01.md", "No headings");
+        assert_eq!(doc.date(), parse_date("2019-03-01"));
+        assert_eq!(doc.title(), "2019-03-01");
+        assert_eq!(
+            doc.output_path(Some("/:year/:slug/")),
+            Ok(PathBuf::from("2019/2019-03-01/index.html"))
+        );
+    }
+
+    #[test]
+    fn test_output_path_from_front_matter() {
+        let doc = document(
+            "notes/hello.md",
+            "---\ndate: 2020-12-24\nslug: greetings\ncategory: Build Systems\n---\n",
+        );
+        assert_eq!(
+            doc.output_path(Some("/:category/:year/:slug/")),
+            Ok(PathBuf::from("build-systems/2020/greetings/index.html"))
+        );
+        assert_eq!(
+            doc.output_path(Some(":path/:slug.htm")),
+            Ok(PathBuf::from("notes/greetings.htm"))
+        );
+    }
+
+    #[test]
+    fn test_output_path_with_missing_fields() {
+        let doc = document("hello.md", "");
+        assert!(doc.output_path(Some("/:year/:slug/")).is_err());
+        assert!(doc.output_path(Some("/:category/:slug/")).is_err());
+    }
+}
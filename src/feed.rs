@@ -0,0 +1,317 @@
+use chrono::NaiveDateTime;
+use nipper::Document as Html;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::html::escape;
+use crate::model::FeedFormat;
+
+/// A document as it appears in a feed. Its content is compiled when the feed
+/// is written, so only where to find it is kept here.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct FeedEntry {
+    pub input: PathBuf,
+    pub title: String,
+    pub url: String,
+    pub date: Option<NaiveDateTime>,
+    pub summary: String,
+    pub links: BTreeMap<String, String>,
+    pub wiki_links: BTreeMap<String, String>,
+}
+
+// Feeds can hold many entries, so we only show where they come from.
+impl std::fmt::Debug for FeedEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.input)
+    }
+}
+
+/// Everything that goes in a feed besides its entries.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Channel {
+    pub title: String,
+    pub link: String,
+    pub url: String,
+}
+
+// Dates in documents have no time zone, so we take them to be in UTC.
+fn rfc2822(date: &NaiveDateTime) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S +0000").to_string()
+}
+
+fn rfc3339(date: &NaiveDateTime) -> String {
+    date.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+/// Resolves a link found in the page at `base` to an absolute URL.
+fn absolute_url(link: &str, base: &str) -> String {
+    let path = link.split(['?', '#']).next().unwrap_or("");
+    let scheme = path
+        .find(':')
+        .is_some_and(|colon| !path[..colon].contains('/'));
+    if scheme || link.starts_with("//") {
+        return link.to_string();
+    }
+
+    let page = base.split('#').next().unwrap_or(base);
+    let origin_len = page
+        .find("://")
+        .map(|start| start + 3)
+        .map(|host| host + page[host..].find('/').unwrap_or(page.len() - host))
+        .unwrap_or(0);
+    let (origin, page_path) = page.split_at(origin_len);
+    if link.is_empty() || link.starts_with('#') {
+        return format!("{}{}", page, link);
+    }
+    let page_path = page_path.split('?').next().unwrap_or("");
+    let joined = if link.starts_with('/') {
+        link.to_string()
+    } else if link.starts_with('?') {
+        format!("{}{}", page_path, link)
+    } else {
+        let dir = &page_path[..page_path.rfind('/').map_or(0, |slash| slash + 1)];
+        format!("/{}{}", dir.trim_start_matches('/'), link)
+    };
+
+    // Resolve `.` and `..` the way browsers do, which never go above the root.
+    let (path, rest) = joined.split_at(joined.find(['?', '#']).unwrap_or(joined.len()));
+    let mut segments: Vec<&str> = vec![];
+    for segment in path.split('/').skip(1) {
+        match segment {
+            "." => (),
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    if path.ends_with("/.") || path.ends_with("/..") {
+        segments.push("");
+    }
+    format!("{}/{}{}", origin, segments.join("/"), rest)
+}
+
+/// Makes every `href` and `src` in an entry absolute, since feed readers
+/// show entries away from the pages they come from.
+fn absolute_links(html: &str, base: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some((start, quote)) = [" href=", " src="]
+        .iter()
+        .filter_map(|attr| {
+            let start = rest.find(attr)? + attr.len();
+            let quote = rest[start..]
+                .chars()
+                .next()
+                .filter(|c| *c == '"' || *c == '\'')?;
+            Some((start + 1, quote))
+        })
+        .min()
+    {
+        let end = match rest[start..].find(quote) {
+            Some(end) => start + end,
+            None => break,
+        };
+        out.push_str(&rest[..start]);
+        out.push_str(&absolute_url(&rest[start..end], base));
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Renders a feed, where every entry comes with the HTML it will show. Links
+/// in that HTML are made absolute to the URL of the entry.
+pub fn render(
+    format: FeedFormat,
+    channel: &Channel,
+    entries: &[(&FeedEntry, String)],
+    updated: NaiveDateTime,
+) -> String {
+    let entries: Vec<(&FeedEntry, String)> = entries
+        .iter()
+        .map(|(entry, html)| (*entry, absolute_links(html, &entry.url)))
+        .collect();
+    let entries = entries.as_slice();
+    match format {
+        FeedFormat::Rss => render_rss(channel, entries, updated),
+        FeedFormat::Atom => render_atom(channel, entries, updated),
+        FeedFormat::Json => render_json(channel, entries),
+    }
+}
+
+fn render_rss(
+    channel: &Channel,
+    entries: &[(&FeedEntry, String)],
+    updated: NaiveDateTime,
+) -> String {
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    out.push_str("<rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\">\n");
+    out.push_str("  <channel>\n");
+    out.push_str(&format!("    <title>{}</title>\n", escape(&channel.title)));
+    out.push_str(&format!("    <link>{}</link>\n", escape(&channel.link)));
+    out.push_str(&format!(
+        "    <description>{}</description>\n",
+        escape(&channel.title)
+    ));
+    out.push_str(&format!(
+        "    <atom:link href=\"{}\" rel=\"self\" type=\"application/rss+xml\"/>\n",
+        escape(&channel.url)
+    ));
+    out.push_str(&format!(
+        "    <lastBuildDate>{}</lastBuildDate>\n",
+        rfc2822(&updated)
+    ));
+    for (entry, html) in entries {
+        out.push_str("    <item>\n");
+        out.push_str(&format!("      <title>{}</title>\n", escape(&entry.title)));
+        out.push_str(&format!("      <link>{}</link>\n", escape(&entry.url)));
+        out.push_str(&format!("      <guid>{}</guid>\n", escape(&entry.url)));
+        if let Some(date) = &entry.date {
+            out.push_str(&format!("      <pubDate>{}</pubDate>\n", rfc2822(date)));
+        }
+        out.push_str(&format!(
+            "      <description>{}</description>\n",
+            escape(html)
+        ));
+        out.push_str("    </item>\n");
+    }
+    out.push_str("  </channel>\n");
+    out.push_str("</rss>\n");
+    out
+}
+
+fn render_atom(
+    channel: &Channel,
+    entries: &[(&FeedEntry, String)],
+    updated: NaiveDateTime,
+) -> String {
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    out.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    out.push_str(&format!("  <title>{}</title>\n", escape(&channel.title)));
+    out.push_str(&format!("  <link href=\"{}\"/>\n", escape(&channel.link)));
+    out.push_str(&format!(
+        "  <link href=\"{}\" rel=\"self\"/>\n",
+        escape(&channel.url)
+    ));
+    out.push_str(&format!("  <id>{}</id>\n", escape(&channel.url)));
+    out.push_str(&format!("  <updated>{}</updated>\n", rfc3339(&updated)));
+    for (entry, html) in entries {
+        out.push_str("  <entry>\n");
+        out.push_str(&format!("    <title>{}</title>\n", escape(&entry.title)));
+        out.push_str(&format!("    <link href=\"{}\"/>\n", escape(&entry.url)));
+        out.push_str(&format!("    <id>{}</id>\n", escape(&entry.url)));
+        out.push_str(&format!(
+            "    <updated>{}</updated>\n",
+            rfc3339(entry.date.as_ref().unwrap_or(&updated))
+        ));
+        out.push_str(&format!(
+            "    <content type=\"html\">{}</content>\n",
+            escape(html)
+        ));
+        out.push_str("  </entry>\n");
+    }
+    out.push_str("</feed>\n");
+    out
+}
+
+fn render_json(channel: &Channel, entries: &[(&FeedEntry, String)]) -> String {
+    let items: Vec<serde_json::Value> = entries
+        .iter()
+        .map(|(entry, html)| {
+            let mut item = serde_json::json!({
+                "id": entry.url,
+                "url": entry.url,
+                "title": entry.title,
+                "content_html": html,
+            });
+            if let Some(date) = &entry.date {
+                item["date_published"] = rfc3339(date).into();
+            }
+            // JSON Feed summaries are plain text, so their links don't matter.
+            let summary = Html::from(entry.summary.as_str())
+                .select("body")
+                .text()
+                .trim()
+                .to_string();
+            if !summary.is_empty() {
+                item["summary"] = summary.into();
+            }
+            item
+        })
+        .collect();
+    let feed = serde_json::json!({
+        "version": "https://jsonfeed.org/version/1.1",
+        "title": channel.title,
+        "home_page_url": channel.link,
+        "feed_url": channel.url,
+        "items": items,
+    });
+    serde_json::to_string_pretty(&feed).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_atom() {
+        let channel = Channel {
+            title: "Notes & Thoughts".to_string(),
+            link: "https://example.com/blog/".to_string(),
+            url: "https://example.com/blog/atom.xml".to_string(),
+        };
+        let date = chrono::NaiveDate::from_ymd_opt(2019, 3, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        let entry = FeedEntry {
+            input: PathBuf::from("blog/hello.md"),
+            title: "Hello".to_string(),
+            url: "https://example.com/blog/hello.html".to_string(),
+            date: Some(date),
+            summary: "".to_string(),
+            links: BTreeMap::new(),
+            wiki_links: BTreeMap::new(),
+        };
+        let atom = render(
+            FeedFormat::Atom,
+            &channel,
+            &[(&entry, "<p>Hi</p>".to_string())],
+            date,
+        );
+        assert!(atom.contains("<title>Notes &amp; Thoughts</title>"));
+        assert!(atom.contains("<updated>2019-03-01T00:00:00Z</updated>"));
+        assert!(atom.contains("<content type=\"html\">&lt;p&gt;Hi&lt;/p&gt;</content>"));
+    }
+
+    #[test]
+    fn test_absolute_links() {
+        let base = "https://example.com/blog/hello.html";
+        let html = "<p><a href=\"/about/\">me</a> <a href=\"../cv.pdf#page=2\">cv</a> \
+                    <img src='img/cat.png'> <a href=\"#intro\">intro</a> \
+                    <a href=\"https://elsewhere.com/\">there</a> \
+                    <a href=\"mailto:me@example.com\">mail</a></p>\
+                    <pre><code>&lt;a href=&quot;x.html&quot;&gt;</code></pre>";
+        assert_eq!(
+            absolute_links(html, base),
+            "<p><a href=\"https://example.com/about/\">me</a> \
+             <a href=\"https://example.com/cv.pdf#page=2\">cv</a> \
+             <img src='https://example.com/blog/img/cat.png'> \
+             <a href=\"https://example.com/blog/hello.html#intro\">intro</a> \
+             <a href=\"https://elsewhere.com/\">there</a> \
+             <a href=\"mailto:me@example.com\">mail</a></p>\
+             <pre><code>&lt;a href=&quot;x.html&quot;&gt;</code></pre>"
+        );
+        assert_eq!(
+            absolute_url("./", "https://example.com/blog/"),
+            "https://example.com/blog/"
+        );
+        assert_eq!(
+            absolute_url("a.html", "https://example.com"),
+            "https://example.com/a.html"
+        );
+    }
+}
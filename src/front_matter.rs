@@ -0,0 +1,160 @@
+use std::collections::BTreeMap;
+
+const DELIMITER: &str = "---";
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum Value {
+    Text(String),
+    List(Vec<String>),
+}
+
+/// The `key: value` fields at the top of a document, delimited by `---`
+/// lines. Values are either plain text or lists, written inline as `[a, b]`
+/// or as `- item` lines under an empty key.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Default)]
+pub struct FrontMatter {
+    fields: BTreeMap<String, Value>,
+}
+
+impl FrontMatter {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        match self.fields.get(key) {
+            Some(Value::Text(text)) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn list(&self, key: &str) -> Vec<String> {
+        match self.fields.get(key) {
+            Some(Value::List(items)) => items.clone(),
+            Some(Value::Text(text)) if !text.is_empty() => vec![text.clone()],
+            _ => vec![],
+        }
+    }
+
+    pub fn flag(&self, key: &str) -> bool {
+        self.get(key) == Some("true")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    let quoted = value.len() >= 2
+        && ((value.starts_with('"') && value.ends_with('"'))
+            || (value.starts_with('\'') && value.ends_with('\'')));
+    if quoted {
+        value[1..value.len() - 1].to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn parse_value(value: &str) -> Value {
+    let value = value.trim();
+    if value.starts_with('[') && value.ends_with(']') {
+        Value::List(
+            value[1..value.len() - 1]
+                .split(',')
+                .map(unquote)
+                .filter(|item| !item.is_empty())
+                .collect(),
+        )
+    } else {
+        Value::Text(unquote(value))
+    }
+}
+
+fn parse_fields(block: &str) -> BTreeMap<String, Value> {
+    let mut fields = BTreeMap::new();
+    let mut last_key: Option<String> = None;
+
+    for line in block.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        if let Some(item) = trimmed.strip_prefix("- ") {
+            if let Some(key) = &last_key {
+                let entry = fields
+                    .entry(key.clone())
+                    .or_insert_with(|| Value::List(vec![]));
+                match entry {
+                    Value::List(items) => items.push(unquote(item)),
+                    Value::Text(_) => *entry = Value::List(vec![unquote(item)]),
+                }
+            }
+            continue;
+        }
+
+        if let Some((key, value)) = trimmed.split_once(':') {
+            let key = key.trim().to_string();
+            fields.insert(key.clone(), parse_value(value));
+            last_key = Some(key);
+        }
+    }
+
+    fields
+}
+
+/// Splits a document into its front matter and its body. Documents without
+/// front matter are returned as they are, with an empty `FrontMatter`.
+pub fn parse(source: &str) -> (FrontMatter, &str) {
+    let rest = match source.strip_prefix(DELIMITER) {
+        Some(rest) if rest.starts_with('\n') || rest.starts_with("\r\n") => rest,
+        _ => return (FrontMatter::default(), source),
+    };
+
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if offset > 0 && line.trim_end() == DELIMITER {
+            let fields = parse_fields(&rest[..offset]);
+            let body = &rest[offset + line.len()..];
+            return (FrontMatter { fields }, body);
+        }
+        offset += line.len();
+    }
+
+    (FrontMatter::default(), source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_without_front_matter() {
+        let (front_matter, body) = parse("# Hello\n---\nworld");
+        assert!(front_matter.is_empty());
+        assert_eq!(body, "# Hello\n---\nworld");
+    }
+
+    #[test]
+    fn test_parse_fields() {
+        let (front_matter, body) = parse(
+            "---\ntitle: \"Hello: World\"\ndate: 2019-03-01\ntags: [rust, ocaml]\ndraft: true\n---\n# Hello\n",
+        );
+        assert_eq!(front_matter.get("title"), Some("Hello: World"));
+        assert_eq!(front_matter.get("date"), Some("2019-03-01"));
+        assert_eq!(front_matter.list("tags"), vec!["rust", "ocaml"]);
+        assert!(front_matter.flag("draft"));
+        assert_eq!(body, "# Hello\n");
+    }
+
+    #[test]
+    fn test_parse_list_items() {
+        let (front_matter, _) = parse("---\ntags:\n  - rust\n  - 'build systems'\n---\n");
+        assert_eq!(front_matter.list("tags"), vec!["rust", "build systems"]);
+    }
+
+    #[test]
+    fn test_parse_unterminated() {
+        let (front_matter, body) = parse("---\ntitle: Hello\n");
+        assert!(front_matter.is_empty());
+        assert_eq!(body, "---\ntitle: Hello\n");
+    }
+}
@@ -0,0 +1,247 @@
+use comrak::nodes::{AstNode, NodeHtmlBlock, NodeValue};
+use std::fmt::Write;
+use std::sync::OnceLock;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Color, Theme, ThemeSet};
+use syntect::html::{
+    css_for_theme_with_class_style, line_tokens_to_classed_spans, styled_line_to_highlighted_html,
+    ClassStyle, IncludeBackground,
+};
+use syntect::parsing::{ParseState, ScopeStack, SyntaxReference, SyntaxSet};
+use syntect::util::LinesWithEndings;
+
+use crate::html::escape;
+use crate::model::Highlight;
+
+fn syntax_set() -> &'static SyntaxSet {
+    static SYNTAX_SET: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAX_SET.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme_set() -> &'static ThemeSet {
+    static THEME_SET: OnceLock<ThemeSet> = OnceLock::new();
+    THEME_SET.get_or_init(ThemeSet::load_defaults)
+}
+
+/// The names of the themes code can be highlighted with.
+pub fn themes() -> Vec<String> {
+    theme_set().themes.keys().cloned().collect()
+}
+
+pub fn find_theme(name: &str) -> Option<&'static Theme> {
+    theme_set().themes.get(name)
+}
+
+/// The stylesheet for code highlighted with CSS classes.
+pub fn theme_css(name: &str) -> Option<String> {
+    css_for_theme_with_class_style(find_theme(name)?, ClassStyle::Spaced).ok()
+}
+
+/// What a fenced code block asks for in its info string, as in
+/// ```` ```rust {1,3-5} linenos ````.
+#[derive(Debug, Default, PartialEq)]
+struct Info {
+    lang: String,
+    highlighted: Vec<(usize, usize)>,
+    line_numbers: bool,
+}
+
+fn parse_info(info: &str) -> Info {
+    let (lang, rest) = info
+        .find(|c: char| c.is_whitespace() || c == '{')
+        .map_or((info, ""), |end| info.split_at(end));
+    let mut info = Info {
+        lang: lang.to_string(),
+        ..Info::default()
+    };
+
+    if let Some(ranges) = rest
+        .find('{')
+        .and_then(|start| rest[start + 1..].split('}').next())
+    {
+        for range in ranges.split(',') {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            if let (Ok(start), Ok(end)) = (start.trim().parse(), end.trim().parse()) {
+                info.highlighted.push((start, end));
+            }
+        }
+    }
+    info.line_numbers = rest.split_whitespace().any(|word| word == "linenos");
+    info
+}
+
+fn css_color(color: Color) -> String {
+    format!(
+        "#{:02x}{:02x}{:02x}{:02x}",
+        color.r, color.g, color.b, color.a
+    )
+}
+
+/// Highlights every line of the code on its own, keeping the scopes that are
+/// still open at the end of a line open on the next one.
+fn classed_lines(code: &str, syntax: &SyntaxReference) -> Vec<String> {
+    let mut state = ParseState::new(syntax);
+    let mut stack = ScopeStack::new();
+    let mut lines = vec![];
+    for line in LinesWithEndings::from(code) {
+        let mut html = String::new();
+        for scope in stack.as_slice() {
+            let _ = write!(
+                html,
+                "<span class=\"{}\">",
+                scope.build_string().replace('.', " ")
+            );
+        }
+        let spans = state.parse_line(line, syntax_set()).ok().and_then(|ops| {
+            line_tokens_to_classed_spans(line, &ops, ClassStyle::Spaced, &mut stack).ok()
+        });
+        match spans {
+            Some((spans, _)) => html.push_str(&spans),
+            None => html.push_str(&escape(line)),
+        }
+        html.push_str(&"</span>".repeat(stack.len()));
+        lines.push(html);
+    }
+    lines
+}
+
+fn inline_lines(code: &str, syntax: &SyntaxReference, theme: &Theme) -> Vec<String> {
+    let mut highlighter = HighlightLines::new(syntax, theme);
+    LinesWithEndings::from(code)
+        .map(|line| {
+            highlighter
+                .highlight_line(line, syntax_set())
+                .ok()
+                .and_then(|styled| {
+                    styled_line_to_highlighted_html(&styled, IncludeBackground::No).ok()
+                })
+                .unwrap_or_else(|| escape(line))
+        })
+        .collect()
+}
+
+/// Highlights a block of code, wrapping every line in a `line` span so they
+/// can be numbered and highlighted.
+pub fn highlight(code: &str, info: &str, config: &Highlight) -> String {
+    let info = parse_info(info);
+    let syntax = syntax_set()
+        .find_syntax_by_token(&info.lang)
+        .unwrap_or_else(|| syntax_set().find_syntax_plain_text());
+    let theme = find_theme(&config.clone().theme());
+    let classes = config.clone().classes() || theme.is_none();
+    let line_numbers = config.clone().line_numbers() || info.line_numbers;
+
+    let lines = match theme {
+        Some(theme) if !classes => inline_lines(code, syntax, theme),
+        _ => classed_lines(code, syntax),
+    };
+
+    let mut out = String::new();
+    match theme
+        .and_then(|theme| theme.settings.background)
+        .filter(|_| !classes)
+    {
+        Some(background) => {
+            let _ = write!(
+                out,
+                "<pre class=\"highlight\" style=\"background-color:{};\">",
+                css_color(background)
+            );
+        }
+        None => out.push_str("<pre class=\"highlight code\">"),
+    }
+    if info.lang.is_empty() {
+        out.push_str("<code>");
+    } else {
+        let _ = write!(out, "<code class=\"language-{}\">", escape(&info.lang));
+    }
+
+    let line_highlight = theme
+        .and_then(|theme| theme.settings.line_highlight)
+        .filter(|_| !classes);
+    for (number, line) in (1..).zip(lines) {
+        let highlighted = info
+            .highlighted
+            .iter()
+            .any(|(start, end)| (*start..=*end).contains(&number));
+        out.push_str("<span class=\"line");
+        if highlighted {
+            out.push_str(" highlighted");
+        }
+        out.push('"');
+        match line_highlight {
+            Some(color) if highlighted => {
+                let _ = write!(
+                    out,
+                    " style=\"display:inline-block;width:100%;background-color:{};\"",
+                    css_color(color)
+                );
+            }
+            _ => (),
+        }
+        out.push('>');
+        if line_numbers {
+            out.push_str("<span class=\"line-number\"");
+            if !classes {
+                out.push_str(" style=\"user-select:none;opacity:0.5;margin-right:1em;\"");
+            }
+            let _ = write!(out, ">{}</span>", number);
+        }
+        // The newline can end up inside the spans of the line.
+        out.push_str(&line.replace('\n', ""));
+        out.push_str("</span>\n");
+    }
+    out.push_str("</code></pre>\n");
+    out
+}
+
+/// Replaces every code block in a Markdown document with its highlighted
+/// HTML.
+pub fn highlight_code_blocks<'a>(root: &'a AstNode<'a>, config: &Highlight) {
+    for node in root.descendants() {
+        let mut ast = node.data.borrow_mut();
+        let html = match &ast.value {
+            NodeValue::CodeBlock(block) => highlight(
+                &String::from_utf8_lossy(&block.literal),
+                &String::from_utf8_lossy(&block.info),
+                config,
+            ),
+            _ => continue,
+        };
+        let mut block = NodeHtmlBlock::default();
+        block.literal = html.into_bytes();
+        ast.value = NodeValue::HtmlBlock(block);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_info() {
+        assert_eq!(
+            parse_info("rust {1,3-5} linenos"),
+            Info {
+                lang: "rust".to_string(),
+                highlighted: vec![(1, 1), (3, 5)],
+                line_numbers: true,
+            }
+        );
+        assert_eq!(parse_info("ocaml{2}").highlighted, vec![(2, 2)]);
+        assert_eq!(parse_info("").lang, "");
+    }
+
+    #[test]
+    fn test_highlight_with_classes() {
+        let config = Highlight::from_sexp(&crate::parser::parse_sexp("(classes true)"));
+        let html = highlight("let x = \"a\nb\";\n", "rust {2}", &config);
+        assert!(html.starts_with("<pre class=\"highlight code\"><code class=\"language-rust\">"));
+        // The string spans two lines, and is closed and reopened in between.
+        let lines: Vec<&str> = html.lines().collect();
+        assert!(lines[0].ends_with("a</span></span></span>"));
+        assert!(
+            lines[1].starts_with("<span class=\"line highlighted\"><span class=\"source rust\">")
+        );
+    }
+}
@@ -0,0 +1,21 @@
+/// Escapes text to be placed in HTML or XML, be it between tags or within a
+/// quoted attribute.
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            escape(r#"<a href="?a=1&b=2">"#),
+            "&lt;a href=&quot;?a=1&amp;b=2&quot;&gt;"
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use log::{error, info};
 
+use crate::build_cache::BuildCache;
 use crate::build_graph;
 use crate::build_rules::Artifact;
 use crate::model::Project;
@@ -73,8 +74,16 @@ async fn wait_for_changes(
     loop {
         tokio::time::delay_for(Duration::from_millis(100)).await;
 
-        let build_plan = build_graph::plan_build(project.clone()).compute_diff();
-        let artifacts = build_plan.execute();
+        let cache = BuildCache::from_project(project.clone());
+        let build_plan = match build_graph::plan_build(project.clone()) {
+            Ok(build_plan) => build_plan.compute_diff(&cache),
+            Err(err) => {
+                error!("{}", err);
+                tokio::time::delay_for(Duration::from_millis(1000)).await;
+                continue;
+            }
+        };
+        let artifacts = build_plan.execute(cache).artifacts();
 
         if !artifacts.is_empty() {
             let artifacts: Vec<String> = artifacts
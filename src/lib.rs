@@ -1,7 +1,25 @@
+pub mod build_cache;
 pub mod build_executor;
+pub mod build_export;
 pub mod build_graph;
+pub mod build_links;
+pub mod build_listings;
+pub mod build_report;
 pub mod build_rules;
+pub mod doc_links;
+pub mod document;
+pub mod feed;
 pub mod file_watcher;
+pub mod front_matter;
+pub mod highlight;
+pub mod html;
 pub mod http_server;
+pub mod link_checker;
+pub mod math;
 pub mod model;
 pub mod parser;
+pub mod search;
+pub mod sitemap;
+pub mod template;
+pub mod toc;
+pub mod trace;
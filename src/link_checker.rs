@@ -0,0 +1,318 @@
+use nipper::Document as Html;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+
+use crate::build_graph::BuildPlan;
+use crate::build_rules::CompilationUnit;
+use crate::model::Project;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    MissingTarget(PathBuf),
+    MissingAnchor(PathBuf, String),
+    OutsideOutput,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::MissingTarget(target) => write!(f, "{:?} does not exist", target),
+            Problem::MissingAnchor(target, anchor) => {
+                write!(f, "{:?} has no anchor named {:?}", target, anchor)
+            }
+            Problem::OutsideOutput => write!(f, "it points outside of the output folder"),
+        }
+    }
+}
+
+/// A link in a page that doesn't lead anywhere.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrokenLink {
+    pub page: PathBuf,
+    pub link: String,
+    pub problem: Problem,
+}
+
+/// The HTML pages in a build plan, along with the documents they are built
+/// from, so broken links can be reported where they need fixing.
+fn pages(plan: &BuildPlan) -> BTreeMap<PathBuf, PathBuf> {
+    let mut pages = BTreeMap::new();
+    for unit in plan.units() {
+        let unit = match unit {
+            CompilationUnit::CacheHit { unit } => unit.as_ref(),
+            unit => unit,
+        };
+        let (source, output) = match unit {
+            CompilationUnit::Compile { input, output, .. }
+            | CompilationUnit::Copy { input, output } => (input.clone(), output.clone()),
+            CompilationUnit::Template { output, .. } | CompilationUnit::Generate { output, .. } => {
+                (output.clone(), output.clone())
+            }
+            _ => continue,
+        };
+        if output.extension().is_some_and(|ext| ext == "html") {
+            pages.entry(output).or_insert(source);
+        }
+    }
+    pages
+}
+
+pub(crate) fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = text
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+/// Resolves `..` and `.` without touching the filesystem, or gives up when
+/// the path goes above where it started.
+pub(crate) fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            component => normalized.push(component),
+        }
+    }
+    Some(normalized)
+}
+
+pub struct LinkChecker {
+    output_dir: PathBuf,
+    base_url: Option<String>,
+    anchors: HashMap<PathBuf, HashSet<String>>,
+}
+
+impl LinkChecker {
+    pub fn new(project: &Project) -> LinkChecker {
+        LinkChecker {
+            output_dir: project.clone().output_dir(),
+            base_url: project.clone().base_url(),
+            anchors: HashMap::new(),
+        }
+    }
+
+    /// Where a link in `page` points to, relative to the output folder, or
+    /// `None` for links that leave the site.
+    fn target(&self, page: &Path, link: &str) -> Option<Result<PathBuf, Problem>> {
+        let link = match &self.base_url {
+            Some(base_url) => link
+                .strip_prefix(base_url.as_str())
+                .filter(|path| path.is_empty() || path.starts_with(['/', '?', '#']))
+                .unwrap_or(link),
+            None => link,
+        };
+        let path = link.split(['?', '#']).next().unwrap_or("");
+        let scheme = path
+            .find(':')
+            .is_some_and(|colon| !path[..colon].contains('/'));
+        if scheme || link.starts_with("//") {
+            return None;
+        }
+
+        let path = percent_decode(path);
+        let page = page.strip_prefix(&self.output_dir).unwrap_or(page);
+        let target = if path.is_empty() {
+            page.to_path_buf()
+        } else if let Some(path) = path.strip_prefix('/') {
+            PathBuf::from(path)
+        } else {
+            page.parent().unwrap_or(Path::new("")).join(path)
+        };
+        Some(normalize(&target).ok_or(Problem::OutsideOutput))
+    }
+
+    fn anchors(&mut self, page: &Path) -> &HashSet<String> {
+        self.anchors.entry(page.to_path_buf()).or_insert_with(|| {
+            let html = Html::from(&std::fs::read_to_string(page).unwrap_or_default());
+            html.select("[id]")
+                .iter()
+                .filter_map(|node| node.attr("id"))
+                .chain(html.select("a[name]").iter().filter_map(|a| a.attr("name")))
+                .map(|id| id.to_string())
+                .collect()
+        })
+    }
+
+    fn check_link(&mut self, page: &Path, link: &str) -> Result<(), Problem> {
+        let target = match self.target(page, link) {
+            Some(target) => self.output_dir.join(target?),
+            None => return Ok(()),
+        };
+        let target = if target.is_dir() {
+            target.join("index.html")
+        } else {
+            target
+        };
+        if !target.is_file() {
+            return Err(Problem::MissingTarget(target));
+        }
+
+        // Only pages have anchors; a fragment on anything else (like
+        // `doc.pdf#page=2`) means something to whatever opens the file.
+        let anchor = link
+            .split_once('#')
+            .filter(|_| target.extension().is_some_and(|ext| ext == "html"))
+            .map(|(_, anchor)| percent_decode(anchor));
+        match anchor {
+            Some(anchor) if !anchor.is_empty() && anchor != "top" => {
+                if self.anchors(&target).contains(&anchor) {
+                    Ok(())
+                } else {
+                    Err(Problem::MissingAnchor(target, anchor))
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Every broken link in a page.
+    pub fn check_page(&mut self, page: &Path) -> Vec<BrokenLink> {
+        let html = Html::from(&std::fs::read_to_string(page).unwrap_or_default());
+        let links: Vec<String> = html
+            .select("[href]")
+            .iter()
+            .filter_map(|node| node.attr("href"))
+            .chain(
+                html.select("[src]")
+                    .iter()
+                    .filter_map(|node| node.attr("src")),
+            )
+            .map(|link| link.trim().to_string())
+            .collect();
+
+        links
+            .into_iter()
+            .filter_map(|link| {
+                self.check_link(page, &link)
+                    .err()
+                    .map(|problem| BrokenLink {
+                        page: page.to_path_buf(),
+                        link,
+                        problem,
+                    })
+            })
+            .collect()
+    }
+}
+
+/// Checks the links in every page a build plan writes, and groups the broken
+/// ones by the document the page is built from.
+pub fn check_links(project: &Project, plan: &BuildPlan) -> BTreeMap<PathBuf, Vec<BrokenLink>> {
+    let mut checker = LinkChecker::new(project);
+    let mut report: BTreeMap<PathBuf, Vec<BrokenLink>> = BTreeMap::new();
+    for (page, source) in pages(plan) {
+        let broken = checker.check_page(&page);
+        if !broken.is_empty() {
+            report.entry(source).or_default().extend(broken);
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target() {
+        let project = Project::new()
+            .with_output_dir(PathBuf::from("/out"))
+            .with_base_url("https://example.com/".to_string());
+        let checker = LinkChecker::new(&project);
+        let page = Path::new("/out/posts/a.html");
+        let target = |link| checker.target(page, link);
+
+        assert_eq!(target("b.html#x"), Some(Ok(PathBuf::from("posts/b.html"))));
+        assert_eq!(
+            target("../index.html"),
+            Some(Ok(PathBuf::from("index.html")))
+        );
+        assert_eq!(target("/about/"), Some(Ok(PathBuf::from("about"))));
+        assert_eq!(target("#top"), Some(Ok(PathBuf::from("posts/a.html"))));
+        assert_eq!(
+            target("https://example.com/my%20cv.pdf"),
+            Some(Ok(PathBuf::from("my cv.pdf")))
+        );
+        assert_eq!(target("../../secret"), Some(Err(Problem::OutsideOutput)));
+        assert_eq!(target("https://elsewhere.com/"), None);
+        assert_eq!(target("mailto:me@example.com"), None);
+    }
+
+    #[test]
+    fn test_check_links() {
+        let root = std::env::temp_dir().join(format!("hotstuff-links-{}", std::process::id()));
+        let (src, out) = (root.join("src"), root.join("out"));
+        std::fs::create_dir_all(out.join("posts")).unwrap();
+        std::fs::write(
+            out.join("index.html"),
+            "<a href=\"posts/a.html#intro\">a</a>\n\
+             <a href=\"posts/a.html#outro\">a</a>\n\
+             <a href=\"doc.pdf#page=2\">pdf</a>\n\
+             <img src=\"missing.png\">\n\
+             <a href=\"https://elsewhere.com/\">elsewhere</a>",
+        )
+        .unwrap();
+        std::fs::write(
+            out.join("posts/a.html"),
+            "<h2 id=\"intro\">Intro</h2><a href=\"../#top\">home</a>",
+        )
+        .unwrap();
+        std::fs::write(out.join("doc.pdf"), [0xff, 0xfe, 0x00]).unwrap();
+
+        let copy = |input: &str, output: &str| {
+            BuildPlan::start_with(CompilationUnit::Copy {
+                input: src.join(input),
+                output: out.join(output),
+            })
+        };
+        let plan = copy("index.html", "index.html").and_then(vec![
+            copy("a.html", "posts/a.html"),
+            copy("doc.pdf", "doc.pdf"),
+        ]);
+        let project = Project::new().with_output_dir(out.clone());
+        let report = check_links(&project, &plan);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let broken = |link: &str, problem| BrokenLink {
+            page: out.join("index.html"),
+            link: link.to_string(),
+            problem,
+        };
+        let mut expected = BTreeMap::new();
+        expected.insert(
+            src.join("index.html"),
+            vec![
+                broken(
+                    "posts/a.html#outro",
+                    Problem::MissingAnchor(out.join("posts/a.html"), "outro".to_string()),
+                ),
+                broken(
+                    "missing.png",
+                    Problem::MissingTarget(out.join("missing.png")),
+                ),
+            ],
+        );
+        assert_eq!(report, expected);
+    }
+}
@@ -1,11 +1,19 @@
 use fern::colors::{Color, ColoredLevelConfig};
-use log::info;
+use log::{error, info, warn};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
+use hotstuff::build_cache::BuildCache;
+use hotstuff::build_export::{self, GraphFormat};
 use hotstuff::build_graph;
+use hotstuff::build_report::{ReportFormat, Status};
+use hotstuff::build_rules::CompilationUnit;
+use hotstuff::highlight;
 use hotstuff::http_server;
+use hotstuff::link_checker::{self, BrokenLink};
 use hotstuff::model;
+use hotstuff::trace::Trace;
 
 #[derive(StructOpt, Debug, Clone)]
 #[structopt(
@@ -19,6 +27,8 @@ It does 2 things:
   * `hotstuff build` - incrementally compile a file tree of documents with assets
   * `hotstuff serve` - serve them with live-reload over HTTP for local development
 
+And `hotstuff check` finds the broken links in what you built.
+
 It keeps no in-memory state, so it has **crazy fast cold starts**.
     "
 )]
@@ -43,8 +53,8 @@ impl HotStuff {
             .info(Color::White)
             .debug(Color::White)
             .trace(Color::BrightBlack);
-        let colors_level = colors_line.clone().info(Color::Green);
-        fern::Dispatch::new()
+        let colors_level = colors_line.info(Color::Green);
+        let dispatch = fern::Dispatch::new()
             .format(move |out, message, record| {
                 out.finish(format_args!(
                     "{color_line}{date} {level}{color_line} :: {message}\x1B[0m",
@@ -62,10 +72,14 @@ impl HotStuff {
             } else {
                 log::LevelFilter::Info
             })
-            .level_for("pretty_colored", log::LevelFilter::Trace)
-            .chain(std::io::stdout())
-            .apply()
-            .unwrap();
+            .level_for("pretty_colored", log::LevelFilter::Trace);
+        // Reports printed as JSON take over stdout, so they can be piped.
+        let dispatch = if self.cmd.prints_json() {
+            dispatch.chain(std::io::stderr())
+        } else {
+            dispatch.chain(std::io::stdout())
+        };
+        dispatch.apply().unwrap();
     }
 }
 
@@ -74,13 +88,29 @@ enum Goal {
     Build(BuildOpt),
 
     Serve(ServeOpt),
+
+    Check(CheckOpt),
+
+    Graph(GraphOpt),
+
+    Explain(ExplainOpt),
+
+    HighlightCss(HighlightCssOpt),
 }
 
 impl Goal {
+    fn prints_json(&self) -> bool {
+        matches!(self, Goal::Build(opts) if opts.format == ReportFormat::Json)
+    }
+
     async fn run(self) {
         match self {
             Goal::Build(opts) => opts.build().await,
             Goal::Serve(opts) => opts.serve().await,
+            Goal::Check(opts) => opts.check(),
+            Goal::Graph(opts) => opts.graph(),
+            Goal::Explain(opts) => opts.explain(),
+            Goal::HighlightCss(opts) => opts.highlight_css(),
         }
     }
 }
@@ -116,17 +146,33 @@ struct ServeOpt {
         parse(from_os_str)
     )]
     output_dir: PathBuf,
+
+    #[structopt(long = "no-drafts", help = "leave out documents marked as drafts")]
+    no_drafts: bool,
+
+    #[structopt(
+        long = "no-future",
+        help = "leave out documents with a publish date in the future"
+    )]
+    no_future: bool,
 }
 
 impl ServeOpt {
     async fn serve(self) {
         let project = model::Project::new()
             .with_root(self.root)
-            .with_output_dir(self.output_dir);
+            .with_project_file()
+            .with_output_dir(self.output_dir)
+            .with_drafts(!self.no_drafts)
+            .with_future(!self.no_future);
 
-        build_graph::plan_build(project.clone())
-            .compute_diff()
-            .execute();
+        let cache = BuildCache::from_project(project.clone());
+        match build_graph::plan_build(project.clone()) {
+            Ok(build_plan) => {
+                build_plan.compute_diff(&cache).execute(cache);
+            }
+            Err(err) => error!("{}", err),
+        }
 
         http_server::Server::from_project(project)
             .with_port(self.port)
@@ -160,6 +206,53 @@ struct BuildOpt {
 
     #[structopt(short = "f", long = "force", help = "execute all compilation units")]
     force: bool,
+
+    #[structopt(long = "drafts", help = "include documents marked as drafts")]
+    drafts: bool,
+
+    #[structopt(
+        long = "future",
+        help = "include documents with a publish date in the future"
+    )]
+    future: bool,
+
+    #[structopt(
+        long = "check-links",
+        help = "fail the build if any page has broken links"
+    )]
+    check_links: bool,
+
+    #[structopt(
+        long = "dry-run",
+        help = "list what would be built, without building anything",
+        conflicts_with_all = &["check-links", "REPORT", "TRACE"]
+    )]
+    dry_run: bool,
+
+    #[structopt(
+        long = "report",
+        name = "REPORT",
+        help = "write a JSON report of every unit in the build to this file",
+        parse(from_os_str)
+    )]
+    report: Option<PathBuf>,
+
+    #[structopt(
+        long = "format",
+        name = "FORMAT",
+        default_value = "text",
+        possible_values = &["text", "json"],
+        help = "print the build report as JSON instead of logging what was built"
+    )]
+    format: ReportFormat,
+
+    #[structopt(
+        long = "trace",
+        name = "TRACE",
+        help = "write where the build spends its time to this file, in the Chrome trace format",
+        parse(from_os_str)
+    )]
+    trace: Option<PathBuf>,
 }
 
 impl BuildOpt {
@@ -168,23 +261,414 @@ impl BuildOpt {
         info!("Building project...");
         let project = model::Project::new()
             .with_root(self.root)
-            .with_output_dir(self.output_dir);
+            .with_project_file()
+            .with_output_dir(self.output_dir)
+            .with_drafts(self.drafts)
+            .with_future(self.future);
 
+        let trace = Trace::new();
+        let cache = BuildCache::from_project(project.clone());
         let build_plan = {
-            let build_plan = build_graph::plan_build(project);
+            let build_plan = match trace.record("plan_build", "plan", || {
+                build_graph::plan_build(project.clone())
+            }) {
+                Ok(build_plan) => build_plan,
+                Err(err) => {
+                    error!("{}", err);
+                    std::process::exit(1);
+                }
+            };
 
             if self.force {
                 build_plan
             } else {
-                build_plan.compute_diff()
+                trace.record("compute_diff", "diff", || build_plan.compute_diff(&cache))
             }
         };
 
-        let _artifacts = build_plan.execute();
+        if self.dry_run {
+            report_dry_run(&build_plan, self.format);
+            return;
+        }
+
+        let report = trace.record("execute", "build", || {
+            build_plan.clone().execute_traced(cache, &trace)
+        });
+        if let Some(path) = &self.report {
+            let json = format!("{:#}\n", report.to_json());
+            if let Err(err) = std::fs::write(path, json) {
+                error!("Could not write the build report to {:?}: {}", path, err);
+                std::process::exit(1);
+            }
+        }
+        if self.format == ReportFormat::Json {
+            println!("{:#}", report.to_json());
+        }
+
+        let failed = report.count(Status::Failed);
+        let broken_links = if self.check_links && failed == 0 {
+            trace.record("check_links", "check", || {
+                link_checker::check_links(&project, &build_plan)
+            })
+        } else {
+            BTreeMap::new()
+        };
+
+        if let Some(path) = &self.trace {
+            let json = format!("{}\n", trace.to_json());
+            if let Err(err) = std::fs::write(path, json) {
+                error!("Could not write the trace to {:?}: {}", path, err);
+                std::process::exit(1);
+            }
+        }
+
+        if failed > 0 {
+            error!(
+                "{} units failed, and {} were blocked by them",
+                failed,
+                report.count(Status::Blocked)
+            );
+            std::process::exit(1);
+        }
+        if report_broken_links(&broken_links) > 0 {
+            std::process::exit(1);
+        }
         info!("Done in {}ms", t0.elapsed().as_millis());
     }
 }
 
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(name = "check", about = "check the links in the built project")]
+struct CheckOpt {
+    #[structopt(
+        short = "r",
+        long = "root",
+        name = "ROOT",
+        default_value = "./",
+        help = "the root folder of the project",
+        parse(from_os_str)
+    )]
+    root: PathBuf,
+
+    #[structopt(
+        short = "o",
+        long = "output",
+        name = "OUTPUT",
+        default_value = "./_public",
+        help = "the folder where the compiled sites are",
+        parse(from_os_str)
+    )]
+    output_dir: PathBuf,
+
+    #[structopt(long = "drafts", help = "include documents marked as drafts")]
+    drafts: bool,
+
+    #[structopt(
+        long = "future",
+        help = "include documents with a publish date in the future"
+    )]
+    future: bool,
+}
+
+impl CheckOpt {
+    fn check(self) {
+        let project = model::Project::new()
+            .with_root(self.root)
+            .with_project_file()
+            .with_output_dir(self.output_dir)
+            .with_drafts(self.drafts)
+            .with_future(self.future);
+
+        let build_plan = match build_graph::plan_build(project.clone()) {
+            Ok(build_plan) => build_plan,
+            Err(err) => {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        };
+
+        let report = link_checker::check_links(&project, &build_plan);
+        if report_broken_links(&report) > 0 {
+            std::process::exit(1);
+        }
+        info!("No broken links");
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(name = "graph", about = "print the build plan of this project")]
+struct GraphOpt {
+    #[structopt(
+        short = "r",
+        long = "root",
+        name = "ROOT",
+        default_value = "./",
+        help = "the root folder of the project",
+        parse(from_os_str)
+    )]
+    root: PathBuf,
+
+    #[structopt(
+        short = "o",
+        long = "output",
+        name = "OUTPUT",
+        default_value = "./_public",
+        help = "the folder where to place the compiled sites",
+        parse(from_os_str)
+    )]
+    output_dir: PathBuf,
+
+    #[structopt(
+        short = "f",
+        long = "format",
+        name = "FORMAT",
+        default_value = "tree",
+        possible_values = &["tree", "dot", "json"],
+        help = "how to print the plan"
+    )]
+    format: GraphFormat,
+
+    #[structopt(
+        long = "diff",
+        help = "compare the plan with the last build, to show what would be rebuilt"
+    )]
+    diff: bool,
+
+    #[structopt(long = "drafts", help = "include documents marked as drafts")]
+    drafts: bool,
+
+    #[structopt(
+        long = "future",
+        help = "include documents with a publish date in the future"
+    )]
+    future: bool,
+}
+
+impl GraphOpt {
+    fn graph(self) {
+        let project = model::Project::new()
+            .with_root(self.root)
+            .with_project_file()
+            .with_output_dir(self.output_dir)
+            .with_drafts(self.drafts)
+            .with_future(self.future);
+
+        let build_plan = match build_graph::plan_build(project.clone()) {
+            Ok(build_plan) => build_plan,
+            Err(err) => {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        };
+        let build_plan = if self.diff {
+            build_plan.compute_diff(&BuildCache::from_project(project))
+        } else {
+            build_plan
+        };
+
+        print!(
+            "{}",
+            build_export::export(&build_plan, self.format, self.diff)
+        );
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(name = "explain", about = "explain why an output would be rebuilt")]
+struct ExplainOpt {
+    #[structopt(
+        name = "PATH",
+        help = "the output to explain, either as is or within the output folder",
+        parse(from_os_str)
+    )]
+    path: PathBuf,
+
+    #[structopt(
+        short = "r",
+        long = "root",
+        name = "ROOT",
+        default_value = "./",
+        help = "the root folder of the project",
+        parse(from_os_str)
+    )]
+    root: PathBuf,
+
+    #[structopt(
+        short = "o",
+        long = "output",
+        name = "OUTPUT",
+        default_value = "./_public",
+        help = "the folder where the compiled sites are",
+        parse(from_os_str)
+    )]
+    output_dir: PathBuf,
+
+    #[structopt(long = "drafts", help = "include documents marked as drafts")]
+    drafts: bool,
+
+    #[structopt(
+        long = "future",
+        help = "include documents with a publish date in the future"
+    )]
+    future: bool,
+}
+
+impl ExplainOpt {
+    fn explain(self) {
+        let project = model::Project::new()
+            .with_root(self.root)
+            .with_project_file()
+            .with_output_dir(self.output_dir.clone())
+            .with_drafts(self.drafts)
+            .with_future(self.future);
+
+        let build_plan = match build_graph::plan_build(project.clone()) {
+            Ok(build_plan) => build_plan,
+            Err(err) => {
+                error!("{}", err);
+                std::process::exit(1);
+            }
+        };
+
+        let cache = BuildCache::from_project(project);
+        let explanations = match build_plan.clone().explain(&cache, &self.path) {
+            explanations if explanations.is_empty() => {
+                build_plan.explain(&cache, &self.output_dir.join(&self.path))
+            }
+            explanations => explanations,
+        };
+        if explanations.is_empty() {
+            error!("Nothing in the build plan works on {:?}", self.path);
+            std::process::exit(1);
+        }
+
+        for explanation in explanations {
+            println!("{}", build_export::describe(&explanation.unit));
+            if explanation.reasons.is_empty() {
+                println!("  is a cache hit");
+            }
+            for reason in explanation.reasons {
+                println!("  runs again because {}", reason);
+            }
+        }
+    }
+}
+
+/// Prints every unit a build would run, and how many of each kind there are,
+/// as JSON when asked to, where every unit has a `planned` status.
+fn report_dry_run(build_plan: &build_graph::BuildPlan, format: ReportFormat) {
+    let mut counts: Vec<(&str, usize)> = vec![];
+    let mut units = vec![];
+    for cunit in build_plan.units() {
+        if let CompilationUnit::CacheHit { .. } = cunit {
+            continue;
+        }
+        match format {
+            ReportFormat::Text => println!("{}", build_export::describe(cunit)),
+            ReportFormat::Json => units.push(serde_json::json!({
+                "rule": cunit.rule(),
+                "inputs": cunit.inputs(),
+                "output": cunit.path(),
+                "status": "planned",
+            })),
+        }
+        match counts.iter_mut().find(|(rule, _)| *rule == cunit.rule()) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((cunit.rule(), 1)),
+        }
+    }
+
+    if format == ReportFormat::Json {
+        let counts: serde_json::Map<String, serde_json::Value> = counts
+            .iter()
+            .map(|(rule, count)| (rule.to_string(), (*count).into()))
+            .collect();
+        println!(
+            "{:#}",
+            serde_json::json!({ "counts": counts, "units": units })
+        );
+        return;
+    }
+
+    let total: usize = counts.iter().map(|(_, count)| count).sum();
+    if total == 0 {
+        info!("Nothing to build");
+        return;
+    }
+    let counts: Vec<String> = counts
+        .iter()
+        .map(|(rule, count)| format!("{} {}", count, rule))
+        .collect();
+    info!("Would run {} units: {}", total, counts.join(", "));
+}
+
+/// Logs every broken link under the document it is in, and returns how many
+/// there are.
+fn report_broken_links(report: &BTreeMap<PathBuf, Vec<BrokenLink>>) -> usize {
+    let mut count = 0;
+    for (source, links) in report {
+        warn!("{:?}", source);
+        for link in links {
+            warn!("  {} -> {}", link.link, link.problem);
+        }
+        count += links.len();
+    }
+    if count > 0 {
+        error!("Found {} broken links in {} documents", count, report.len());
+    }
+    count
+}
+
+#[derive(StructOpt, Debug, Clone)]
+#[structopt(
+    name = "highlight-css",
+    about = "print the stylesheet for code highlighted with CSS classes"
+)]
+struct HighlightCssOpt {
+    #[structopt(
+        short = "t",
+        long = "theme",
+        name = "THEME",
+        default_value = "base16-ocean.dark",
+        help = "the theme to generate the stylesheet for"
+    )]
+    theme: String,
+
+    #[structopt(
+        short = "o",
+        long = "output",
+        name = "OUTPUT",
+        help = "the file to write the stylesheet to, instead of printing it",
+        parse(from_os_str)
+    )]
+    output: Option<PathBuf>,
+}
+
+impl HighlightCssOpt {
+    fn highlight_css(self) {
+        let css = match highlight::theme_css(&self.theme) {
+            Some(css) => css,
+            None => {
+                error!(
+                    "Unknown theme {:?}, try one of: {}",
+                    self.theme,
+                    highlight::themes().join(", ")
+                );
+                std::process::exit(1);
+            }
+        };
+        match self.output {
+            Some(output) => {
+                if let Err(err) = std::fs::write(&output, css) {
+                    error!("Could not write {:?}: {}", output, err);
+                    std::process::exit(1);
+                }
+            }
+            None => print!("{}", css),
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     HotStuff::from_args().run().await;
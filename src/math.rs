@@ -0,0 +1,1219 @@
+use comrak::nodes::{AstNode, NodeValue};
+use std::fmt::Write;
+
+use crate::html::escape;
+
+/// A formula in a Markdown document, with the line it starts on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Formula {
+    pub tex: String,
+    pub display: bool,
+    pub line: usize,
+}
+
+impl Formula {
+    /// The formula as it was written.
+    fn source(&self) -> String {
+        let delimiter = if self.display { "$$" } else { "$" };
+        format!("{}{}{}", delimiter, self.tex, delimiter)
+    }
+}
+
+/// What went wrong in a formula, and where in it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MathError {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for MathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for MathError {}
+
+type Result<T> = std::result::Result<T, MathError>;
+
+// Formulas are swapped for these while the Markdown around them is compiled,
+// so that nothing in them is taken for emphasis or escapes.
+const PLACEHOLDER_START: char = '\u{E000}';
+const PLACEHOLDER_END: char = '\u{E001}';
+
+fn placeholder(index: usize) -> String {
+    format!("{}{}{}", PLACEHOLDER_START, index, PLACEHOLDER_END)
+}
+
+/// Takes the `$...$` and `$$...$$` formulas out of a Markdown document,
+/// leaving placeholders behind. Fenced code blocks and code spans are left
+/// alone, and `\$` is a dollar sign. Whether a placeholder really stands for
+/// a formula is only known once the document is parsed, see
+/// `formulas_in_text`.
+pub fn extract(source: &str) -> (String, Vec<Formula>) {
+    let mut out = String::with_capacity(source.len());
+    let mut formulas = vec![];
+    let mut fence: Option<String> = None;
+    let mut paragraph = String::new();
+    let mut paragraph_line = 1;
+
+    for (number, line) in (1..).zip(source.split_inclusive('\n')) {
+        let trimmed = line.trim_start_matches(' ');
+        let indent = line.len() - trimmed.len();
+        let marker: String = trimmed
+            .chars()
+            .take_while(|c| *c == '`' || *c == '~')
+            .collect();
+
+        match &fence {
+            Some(open) => {
+                if indent < 4
+                    && marker.starts_with(&open[..1])
+                    && marker.len() >= open.len()
+                    && trimmed[marker.len()..].trim().is_empty()
+                {
+                    fence = None;
+                }
+                out.push_str(line);
+                continue;
+            }
+            None if indent < 4
+                && marker.len() >= 3
+                && marker.chars().all(|c| marker.starts_with(c)) =>
+            {
+                extract_paragraph(&paragraph, paragraph_line, &mut out, &mut formulas);
+                paragraph.clear();
+                fence = Some(marker);
+                out.push_str(line);
+                continue;
+            }
+            None => (),
+        }
+
+        // Formulas never go past the end of a paragraph.
+        if paragraph.is_empty() {
+            paragraph_line = number;
+        }
+        paragraph.push_str(line);
+        if line.trim().is_empty() {
+            extract_paragraph(&paragraph, paragraph_line, &mut out, &mut formulas);
+            paragraph.clear();
+        }
+    }
+    extract_paragraph(&paragraph, paragraph_line, &mut out, &mut formulas);
+
+    (out, formulas)
+}
+
+fn extract_paragraph(text: &str, first_line: usize, out: &mut String, formulas: &mut Vec<Formula>) {
+    let bytes = text.as_bytes();
+    let line_of = |at: usize| first_line + text[..at].matches('\n').count();
+    let mut copied = 0;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'`' => {
+                let run = bytes[i..].iter().take_while(|b| **b == b'`').count();
+                let fence = &text[i..i + run];
+                let mut end = None;
+                let mut j = i + run;
+                while let Some(found) = text[j..].find(fence) {
+                    let at = j + found;
+                    let len = bytes[at..].iter().take_while(|b| **b == b'`').count();
+                    if len == run {
+                        end = Some(at + run);
+                        break;
+                    }
+                    j = at + len;
+                }
+                i = end.unwrap_or(i + run);
+            }
+            b'$' if bytes.get(i + 1) == Some(&b'$') => match text[i + 2..].find("$$") {
+                Some(len) => {
+                    out.push_str(&text[copied..i]);
+                    out.push_str(&placeholder(formulas.len()));
+                    formulas.push(Formula {
+                        tex: text[i + 2..i + 2 + len].to_string(),
+                        display: true,
+                        line: line_of(i),
+                    });
+                    i += len + 4;
+                    copied = i;
+                }
+                None => i += 2,
+            },
+            b'$' => match closing_dollar(text, i + 1) {
+                Some(end) => {
+                    out.push_str(&text[copied..i]);
+                    out.push_str(&placeholder(formulas.len()));
+                    formulas.push(Formula {
+                        tex: text[i + 1..end].to_string(),
+                        display: false,
+                        line: line_of(i),
+                    });
+                    i = end + 1;
+                    copied = i;
+                }
+                None => i += 1,
+            },
+            _ => i += 1,
+        }
+    }
+    out.push_str(&text[copied.min(text.len())..]);
+}
+
+/// Where an inline formula starting at `start` ends. Like in Pandoc, the
+/// formula can't start or end with a space, and can't be followed by a digit,
+/// so that prices like $5 and $10 are left alone.
+fn closing_dollar(text: &str, start: usize) -> Option<usize> {
+    let bytes = text.as_bytes();
+    if start >= bytes.len() || bytes[start].is_ascii_whitespace() {
+        return None;
+    }
+    let mut i = start;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'$' if i > start
+                && !bytes[i - 1].is_ascii_whitespace()
+                && !bytes.get(i + 1).is_some_and(u8::is_ascii_digit) =>
+            {
+                return Some(i);
+            }
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+/// The formulas whose placeholders are in `text`.
+fn placeholders(text: &str) -> Vec<usize> {
+    text.split(PLACEHOLDER_START)
+        .skip(1)
+        .filter_map(|rest| rest.split(PLACEHOLDER_END).next()?.parse().ok())
+        .collect()
+}
+
+fn restore(bytes: &mut Vec<u8>, sources: &[String]) {
+    if !placeholders(&String::from_utf8_lossy(bytes)).is_empty() {
+        *bytes = replace_placeholders(&String::from_utf8_lossy(bytes), sources).into_bytes();
+    }
+}
+
+/// Which formulas ended up in the text of a parsed document. Formulas that
+/// landed anywhere else, like in code, raw HTML, link destinations or image
+/// descriptions, were never formulas to begin with, so they are put back as
+/// they were written.
+pub fn formulas_in_text<'a>(root: &'a AstNode<'a>, formulas: &[Formula]) -> Vec<bool> {
+    let sources: Vec<String> = formulas.iter().map(Formula::source).collect();
+    let mut in_text = vec![false; formulas.len()];
+    for node in root.descendants() {
+        let in_image = node
+            .ancestors()
+            .skip(1)
+            .any(|parent| matches!(parent.data.borrow().value, NodeValue::Image(_)));
+        match &mut node.data.borrow_mut().value {
+            NodeValue::Text(text) if !in_image => {
+                for index in placeholders(&String::from_utf8_lossy(text)) {
+                    if let Some(in_text) = in_text.get_mut(index) {
+                        *in_text = true;
+                    }
+                }
+            }
+            NodeValue::Text(bytes)
+            | NodeValue::Code(bytes)
+            | NodeValue::HtmlInline(bytes)
+            | NodeValue::FootnoteReference(bytes) => restore(bytes, &sources),
+            NodeValue::CodeBlock(block) => {
+                restore(&mut block.info, &sources);
+                restore(&mut block.literal, &sources);
+            }
+            NodeValue::HtmlBlock(block) => restore(&mut block.literal, &sources),
+            NodeValue::Link(link) | NodeValue::Image(link) => {
+                restore(&mut link.url, &sources);
+                restore(&mut link.title, &sources);
+            }
+            _ => (),
+        }
+    }
+    in_text
+}
+
+/// Puts rendered formulas back where their placeholders are.
+pub fn replace_placeholders(html: &str, rendered: &[String]) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find(PLACEHOLDER_START) {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + PLACEHOLDER_START.len_utf8()..];
+        let formula = after.find(PLACEHOLDER_END).and_then(|end| {
+            let index: usize = after[..end].parse().ok()?;
+            Some((rendered.get(index)?, end))
+        });
+        match formula {
+            Some((formula, end)) => {
+                out.push_str(formula);
+                rest = &after[end + PLACEHOLDER_END.len_utf8()..];
+            }
+            None => {
+                out.push(PLACEHOLDER_START);
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Char(char),
+    Number(String),
+    Command(String),
+    Open,
+    Close,
+    Sup,
+    Sub,
+    Prime,
+    Amp,
+    NewRow,
+    End,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Ident(String, Option<&'static str>),
+    Number(String),
+    Op(String),
+    // Operators like sums and limits, that take their scripts above and below.
+    Limits(String),
+    Delimiter(String, &'static str),
+    Text(String),
+    Space(&'static str),
+    Row(Vec<Node>),
+    Frac(Box<Node>, Box<Node>, bool),
+    Sqrt(Box<Node>, Option<Box<Node>>),
+    Scripts(Box<Node>, Option<Box<Node>>, Option<Box<Node>>),
+    Accent {
+        base: Box<Node>,
+        accent: &'static str,
+        stretchy: bool,
+        under: bool,
+        limits: bool,
+    },
+    UnderOver(Box<Node>, Option<Box<Node>>, Option<Box<Node>>),
+    Fenced(String, Vec<Node>, String),
+    Table(Vec<Vec<Node>>, &'static str),
+}
+
+impl Node {
+    fn takes_limits(&self) -> bool {
+        match self {
+            Node::Limits(_) => true,
+            Node::Accent { limits, .. } => *limits,
+            _ => false,
+        }
+    }
+
+    fn with_variant(self, variant: &'static str) -> Node {
+        match self {
+            Node::Ident(name, _) => Node::Ident(name, Some(variant)),
+            Node::Row(nodes) => Node::Row(
+                nodes
+                    .into_iter()
+                    .map(|node| node.with_variant(variant))
+                    .collect(),
+            ),
+            Node::Scripts(base, sub, sup) => {
+                Node::Scripts(Box::new(base.with_variant(variant)), sub, sup)
+            }
+            node => node,
+        }
+    }
+}
+
+const GREEK: &[(&str, &str)] = &[
+    ("alpha", "α"),
+    ("beta", "β"),
+    ("gamma", "γ"),
+    ("delta", "δ"),
+    ("epsilon", "ϵ"),
+    ("varepsilon", "ε"),
+    ("zeta", "ζ"),
+    ("eta", "η"),
+    ("theta", "θ"),
+    ("vartheta", "ϑ"),
+    ("iota", "ι"),
+    ("kappa", "κ"),
+    ("lambda", "λ"),
+    ("mu", "μ"),
+    ("nu", "ν"),
+    ("xi", "ξ"),
+    ("pi", "π"),
+    ("varpi", "ϖ"),
+    ("rho", "ρ"),
+    ("varrho", "ϱ"),
+    ("sigma", "σ"),
+    ("varsigma", "ς"),
+    ("tau", "τ"),
+    ("upsilon", "υ"),
+    ("phi", "ϕ"),
+    ("varphi", "φ"),
+    ("chi", "χ"),
+    ("psi", "ψ"),
+    ("omega", "ω"),
+];
+
+// Capital Greek letters are upright, unlike other variables.
+const CAPITAL_GREEK: &[(&str, &str)] = &[
+    ("Gamma", "Γ"),
+    ("Delta", "Δ"),
+    ("Theta", "Θ"),
+    ("Lambda", "Λ"),
+    ("Xi", "Ξ"),
+    ("Pi", "Π"),
+    ("Sigma", "Σ"),
+    ("Upsilon", "Υ"),
+    ("Phi", "Φ"),
+    ("Psi", "Ψ"),
+    ("Omega", "Ω"),
+];
+
+const SYMBOLS: &[(&str, &str)] = &[
+    ("infty", "∞"),
+    ("partial", "∂"),
+    ("nabla", "∇"),
+    ("ell", "ℓ"),
+    ("hbar", "ℏ"),
+    ("emptyset", "∅"),
+    ("varnothing", "∅"),
+    ("aleph", "ℵ"),
+    ("Re", "ℜ"),
+    ("Im", "ℑ"),
+];
+
+const OPERATORS: &[(&str, &str)] = &[
+    ("pm", "±"),
+    ("mp", "∓"),
+    ("times", "×"),
+    ("div", "÷"),
+    ("cdot", "⋅"),
+    ("ast", "∗"),
+    ("star", "⋆"),
+    ("circ", "∘"),
+    ("bullet", "∙"),
+    ("oplus", "⊕"),
+    ("otimes", "⊗"),
+    ("cup", "∪"),
+    ("cap", "∩"),
+    ("setminus", "∖"),
+    ("wedge", "∧"),
+    ("land", "∧"),
+    ("vee", "∨"),
+    ("lor", "∨"),
+    ("neg", "¬"),
+    ("lnot", "¬"),
+    ("leq", "≤"),
+    ("le", "≤"),
+    ("geq", "≥"),
+    ("ge", "≥"),
+    ("neq", "≠"),
+    ("ne", "≠"),
+    ("approx", "≈"),
+    ("equiv", "≡"),
+    ("sim", "∼"),
+    ("simeq", "≃"),
+    ("cong", "≅"),
+    ("propto", "∝"),
+    ("ll", "≪"),
+    ("gg", "≫"),
+    ("in", "∈"),
+    ("notin", "∉"),
+    ("ni", "∋"),
+    ("subset", "⊂"),
+    ("supset", "⊃"),
+    ("subseteq", "⊆"),
+    ("supseteq", "⊇"),
+    ("to", "→"),
+    ("rightarrow", "→"),
+    ("leftarrow", "←"),
+    ("gets", "←"),
+    ("Rightarrow", "⇒"),
+    ("Leftarrow", "⇐"),
+    ("leftrightarrow", "↔"),
+    ("Leftrightarrow", "⇔"),
+    ("implies", "⟹"),
+    ("iff", "⟺"),
+    ("mapsto", "↦"),
+    ("uparrow", "↑"),
+    ("downarrow", "↓"),
+    ("forall", "∀"),
+    ("exists", "∃"),
+    ("nexists", "∄"),
+    ("mid", "∣"),
+    ("parallel", "∥"),
+    ("perp", "⊥"),
+    ("angle", "∠"),
+    ("therefore", "∴"),
+    ("because", "∵"),
+    ("ldots", "…"),
+    ("dots", "…"),
+    ("cdots", "⋯"),
+    ("vdots", "⋮"),
+    ("ddots", "⋱"),
+    ("colon", ":"),
+    ("int", "∫"),
+    ("iint", "∬"),
+    ("iiint", "∭"),
+    ("oint", "∮"),
+    ("{", "{"),
+    ("}", "}"),
+    ("|", "‖"),
+    ("%", "%"),
+    ("$", "$"),
+    ("#", "#"),
+    ("&", "&"),
+    ("_", "_"),
+];
+
+const LIMITS: &[(&str, &str)] = &[
+    ("sum", "∑"),
+    ("prod", "∏"),
+    ("coprod", "∐"),
+    ("bigcup", "⋃"),
+    ("bigcap", "⋂"),
+    ("bigoplus", "⨁"),
+    ("bigotimes", "⨂"),
+    ("bigvee", "⋁"),
+    ("bigwedge", "⋀"),
+    ("lim", "lim"),
+    ("limsup", "lim sup"),
+    ("liminf", "lim inf"),
+    ("max", "max"),
+    ("min", "min"),
+    ("sup", "sup"),
+    ("inf", "inf"),
+    ("det", "det"),
+    ("gcd", "gcd"),
+    ("Pr", "Pr"),
+];
+
+const FUNCTIONS: &[&str] = &[
+    "sin", "cos", "tan", "cot", "sec", "csc", "sinh", "cosh", "tanh", "coth", "arcsin", "arccos",
+    "arctan", "log", "ln", "lg", "exp", "deg", "dim", "ker", "hom", "arg",
+];
+
+const DELIMITERS: &[(&str, &str)] = &[
+    ("{", "{"),
+    ("}", "}"),
+    ("|", "‖"),
+    ("langle", "⟨"),
+    ("rangle", "⟩"),
+    ("lfloor", "⌊"),
+    ("rfloor", "⌋"),
+    ("lceil", "⌈"),
+    ("rceil", "⌉"),
+    ("vert", "|"),
+    ("Vert", "‖"),
+    ("uparrow", "↑"),
+    ("downarrow", "↓"),
+];
+
+const FONTS: &[(&str, &str)] = &[
+    ("mathbf", "bold"),
+    ("mathit", "italic"),
+    ("mathrm", "normal"),
+    ("mathbb", "double-struck"),
+    ("mathcal", "script"),
+    ("mathfrak", "fraktur"),
+    ("mathsf", "sans-serif"),
+    ("mathtt", "monospace"),
+    ("boldsymbol", "bold-italic"),
+];
+
+// The accent, whether it stretches over what it is on, whether it goes below
+// it, and whether scripts go above and below it.
+const ACCENTS: &[(&str, &str, bool, bool, bool)] = &[
+    ("hat", "^", false, false, false),
+    ("widehat", "^", true, false, false),
+    ("bar", "¯", false, false, false),
+    ("overline", "‾", true, false, false),
+    ("vec", "→", false, false, false),
+    ("overrightarrow", "→", true, false, false),
+    ("tilde", "~", false, false, false),
+    ("widetilde", "~", true, false, false),
+    ("dot", "˙", false, false, false),
+    ("ddot", "¨", false, false, false),
+    ("underline", "_", true, true, false),
+    ("overbrace", "⏞", true, false, true),
+    ("underbrace", "⏟", true, true, true),
+];
+
+const SPACES: &[(&str, &str)] = &[
+    (",", "0.1667em"),
+    (":", "0.2222em"),
+    (">", "0.2222em"),
+    (";", "0.2778em"),
+    ("!", "-0.1667em"),
+    (" ", "0.25em"),
+    ("quad", "1em"),
+    ("qquad", "2em"),
+];
+
+const SIZES: &[(&str, &str)] = &[
+    ("big", "1.2em"),
+    ("Big", "1.8em"),
+    ("bigg", "2.4em"),
+    ("Bigg", "3em"),
+];
+
+fn lookup<T: Copy>(table: &[(&str, T)], name: &str) -> Option<T> {
+    table
+        .iter()
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| *value)
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, offset: usize, message: impl Into<String>) -> Result<T> {
+        Err(MathError {
+            offset,
+            message: message.into(),
+        })
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.src[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// The next token, where it starts, and where it ends.
+    fn lex(&mut self) -> (Token, usize, usize) {
+        self.skip_whitespace();
+        let start = self.pos;
+        let rest = &self.src[start..];
+        let mut chars = rest.chars();
+        let token = match chars.next() {
+            None => return (Token::End, start, start),
+            Some('\\') => {
+                let name: String = chars
+                    .clone()
+                    .take_while(char::is_ascii_alphabetic)
+                    .collect();
+                if !name.is_empty() {
+                    return (Token::Command(name.clone()), start, start + 1 + name.len());
+                }
+                match chars.next() {
+                    Some('\\') => return (Token::NewRow, start, start + 2),
+                    Some(c) => {
+                        return (
+                            Token::Command(c.to_string()),
+                            start,
+                            start + 1 + c.len_utf8(),
+                        )
+                    }
+                    None => Token::Command("".to_string()),
+                }
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let mut len = 0;
+                let bytes = rest.as_bytes();
+                while len < bytes.len()
+                    && (bytes[len].is_ascii_digit()
+                        || (bytes[len] == b'.'
+                            && bytes.get(len + 1).is_some_and(u8::is_ascii_digit)))
+                {
+                    len += 1;
+                }
+                return (Token::Number(rest[..len].to_string()), start, start + len);
+            }
+            Some('{') => Token::Open,
+            Some('}') => Token::Close,
+            Some('^') => Token::Sup,
+            Some('_') => Token::Sub,
+            Some('\'') => Token::Prime,
+            Some('&') => Token::Amp,
+            Some(c) => {
+                return (Token::Char(c), start, start + c.len_utf8());
+            }
+        };
+        (token, start, start + 1)
+    }
+
+    fn peek(&mut self) -> Token {
+        let pos = self.pos;
+        let (token, _, _) = self.lex();
+        self.pos = pos;
+        token
+    }
+
+    fn next(&mut self) -> (Token, usize) {
+        let (token, start, end) = self.lex();
+        self.pos = end;
+        (token, start)
+    }
+
+    /// Everything up to the end of the current group, cell or row.
+    fn parse_list(&mut self) -> Result<Vec<Node>> {
+        let mut nodes = vec![];
+        loop {
+            match self.peek() {
+                Token::Close | Token::Amp | Token::NewRow | Token::End => break,
+                Token::Command(name) if name == "end" || name == "right" => break,
+                _ => nodes.push(self.parse_scripted()?),
+            }
+        }
+        Ok(nodes)
+    }
+
+    fn parse_group(&mut self) -> Result<Node> {
+        let nodes = self.parse_list()?;
+        match self.next() {
+            (Token::Close, _) => Ok(Node::Row(nodes)),
+            (_, at) => self.error(at, "missing `}`"),
+        }
+    }
+
+    /// The argument of a command or script: a group or a single token.
+    fn parse_argument(&mut self, of: &str) -> Result<Node> {
+        let pos = self.pos;
+        match self.next() {
+            (Token::Open, _) => self.parse_group(),
+            // Only the first digit of a number is an argument, as in `x^23`.
+            (Token::Number(number), start) => {
+                self.pos = start + 1;
+                Ok(Node::Number(number[..1].to_string()))
+            }
+            (Token::Close | Token::Amp | Token::NewRow | Token::End, at)
+            | (Token::Sup | Token::Sub | Token::Prime, at) => {
+                self.error(at, format!("missing argument for `{}`", of))
+            }
+            _ => {
+                self.pos = pos;
+                self.parse_atom()
+            }
+        }
+    }
+
+    fn parse_raw_group(&mut self, of: &str) -> Result<String> {
+        match self.next() {
+            (Token::Open, _) => (),
+            (_, at) => return self.error(at, format!("missing argument for `{}`", of)),
+        }
+        let start = self.pos;
+        let mut depth = 0;
+        for (i, c) in self.src[start..].char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' if depth == 0 => {
+                    self.pos = start + i + 1;
+                    return Ok(self.src[start..start + i].to_string());
+                }
+                '}' => depth -= 1,
+                _ => (),
+            }
+        }
+        self.error(start, "missing `}`")
+    }
+
+    fn parse_delimiter(&mut self, of: &str) -> Result<String> {
+        match self.next() {
+            (Token::Char('.'), _) => Ok("".to_string()),
+            (Token::Char('<'), _) => Ok("⟨".to_string()),
+            (Token::Char('>'), _) => Ok("⟩".to_string()),
+            (Token::Char(c @ ('(' | ')' | '[' | ']' | '|' | '/')), _) => Ok(c.to_string()),
+            (Token::Command(name), at) => match lookup(DELIMITERS, &name) {
+                Some(delimiter) => Ok(delimiter.to_string()),
+                None => self.error(at, format!("`\\{}` is not a delimiter", name)),
+            },
+            (_, at) => self.error(at, format!("missing delimiter after `\\{}`", of)),
+        }
+    }
+
+    fn parse_scripted(&mut self) -> Result<Node> {
+        let base = match self.peek() {
+            Token::Sup | Token::Sub | Token::Prime => Node::Row(vec![]),
+            _ => self.parse_atom()?,
+        };
+        let mut sub = None;
+        let mut sup: Option<Node> = None;
+        let mut primes = String::new();
+        loop {
+            match self.peek() {
+                Token::Sup => {
+                    let (_, at) = self.next();
+                    if sup.is_some() {
+                        return self.error(at, "double superscript");
+                    }
+                    sup = Some(self.parse_argument("^")?);
+                }
+                Token::Sub => {
+                    let (_, at) = self.next();
+                    if sub.is_some() {
+                        return self.error(at, "double subscript");
+                    }
+                    sub = Some(self.parse_argument("_")?);
+                }
+                Token::Prime => {
+                    self.next();
+                    primes.push('′');
+                }
+                _ => break,
+            }
+        }
+        if !primes.is_empty() {
+            let primes = Node::Op(primes);
+            sup = Some(match sup {
+                Some(sup) => Node::Row(vec![primes, sup]),
+                None => primes,
+            });
+        }
+        if sub.is_none() && sup.is_none() {
+            return Ok(base);
+        }
+        let (sub, sup) = (sub.map(Box::new), sup.map(Box::new));
+        if base.takes_limits() {
+            Ok(Node::UnderOver(Box::new(base), sub, sup))
+        } else {
+            Ok(Node::Scripts(Box::new(base), sub, sup))
+        }
+    }
+
+    fn parse_atom(&mut self) -> Result<Node> {
+        match self.next() {
+            (Token::Char(c), _) if c.is_alphabetic() => Ok(Node::Ident(c.to_string(), None)),
+            (Token::Char('-'), _) => Ok(Node::Op("−".to_string())),
+            (Token::Char('*'), _) => Ok(Node::Op("∗".to_string())),
+            (Token::Char('~'), _) => Ok(Node::Space("0.25em")),
+            (Token::Char(c), _) => Ok(Node::Op(c.to_string())),
+            (Token::Number(number), _) => Ok(Node::Number(number)),
+            (Token::Open, _) => self.parse_group(),
+            (Token::Command(name), at) => self.parse_command(&name, at),
+            (Token::Close, at) => self.error(at, "unexpected `}`"),
+            (Token::Amp, at) => self.error(at, "unexpected `&` outside of an environment"),
+            (Token::NewRow, at) => self.error(at, "unexpected `\\\\` outside of an environment"),
+            (token, at) => self.error(at, format!("unexpected {:?}", token)),
+        }
+    }
+
+    fn parse_command(&mut self, name: &str, at: usize) -> Result<Node> {
+        if let Some(letter) = lookup(GREEK, name).or_else(|| lookup(SYMBOLS, name)) {
+            return Ok(Node::Ident(letter.to_string(), None));
+        }
+        if let Some(letter) = lookup(CAPITAL_GREEK, name) {
+            return Ok(Node::Ident(letter.to_string(), Some("normal")));
+        }
+        if let Some(op) = lookup(OPERATORS, name) {
+            return Ok(Node::Op(op.to_string()));
+        }
+        if let Some(op) = lookup(LIMITS, name) {
+            return Ok(Node::Limits(op.to_string()));
+        }
+        if FUNCTIONS.contains(&name) {
+            return Ok(Node::Ident(name.to_string(), None));
+        }
+        if let Some(width) = lookup(SPACES, name) {
+            return Ok(Node::Space(width));
+        }
+        if let Some(variant) = lookup(FONTS, name) {
+            return Ok(self.parse_argument(name)?.with_variant(variant));
+        }
+        if let Some(size) = lookup(SIZES, name.trim_end_matches(['l', 'r', 'm'])) {
+            return Ok(Node::Delimiter(self.parse_delimiter(name)?, size));
+        }
+        if let Some((_, accent, stretchy, under, limits)) =
+            ACCENTS.iter().find(|(accent, ..)| *accent == name)
+        {
+            return Ok(Node::Accent {
+                base: Box::new(self.parse_argument(name)?),
+                accent,
+                stretchy: *stretchy,
+                under: *under,
+                limits: *limits,
+            });
+        }
+
+        match name {
+            "frac" | "dfrac" | "tfrac" | "cfrac" => {
+                let numerator = self.parse_argument(name)?;
+                let denominator = self.parse_argument(name)?;
+                Ok(Node::Frac(Box::new(numerator), Box::new(denominator), true))
+            }
+            "binom" => {
+                let n = self.parse_argument(name)?;
+                let k = self.parse_argument(name)?;
+                let frac = Node::Frac(Box::new(n), Box::new(k), false);
+                Ok(Node::Fenced("(".to_string(), vec![frac], ")".to_string()))
+            }
+            "sqrt" => {
+                let index = match self.peek() {
+                    Token::Char('[') => {
+                        self.next();
+                        let mut nodes = vec![];
+                        loop {
+                            match self.peek() {
+                                Token::Char(']') => {
+                                    self.next();
+                                    break;
+                                }
+                                Token::End => return self.error(self.pos, "missing `]`"),
+                                _ => nodes.push(self.parse_scripted()?),
+                            }
+                        }
+                        Some(Box::new(Node::Row(nodes)))
+                    }
+                    _ => None,
+                };
+                Ok(Node::Sqrt(Box::new(self.parse_argument(name)?), index))
+            }
+            "overset" | "stackrel" | "underset" => {
+                let script = Box::new(self.parse_argument(name)?);
+                let base = Box::new(self.parse_argument(name)?);
+                Ok(match name {
+                    "underset" => Node::UnderOver(base, Some(script), None),
+                    _ => Node::UnderOver(base, None, Some(script)),
+                })
+            }
+            "text" | "textrm" | "mbox" => Ok(Node::Text(self.parse_raw_group(name)?)),
+            "operatorname" => Ok(Node::Ident(self.parse_raw_group(name)?, None)),
+            "left" => {
+                let open = self.parse_delimiter(name)?;
+                let nodes = self.parse_list()?;
+                match self.next() {
+                    (Token::Command(right), _) if right == "right" => {
+                        let close = self.parse_delimiter("right")?;
+                        Ok(Node::Fenced(open, nodes, close))
+                    }
+                    (_, at) => self.error(at, "missing `\\right`"),
+                }
+            }
+            "right" => self.error(at, "`\\right` without `\\left`"),
+            "begin" => self.parse_environment(),
+            "end" => self.error(at, "`\\end` without `\\begin`"),
+            _ => self.error(at, format!("unknown command `\\{}`", name)),
+        }
+    }
+
+    fn parse_environment(&mut self) -> Result<Node> {
+        let at = self.pos;
+        let name = self.parse_raw_group("begin")?;
+        let (open, close, align) = match name.as_str() {
+            "matrix" => ("", "", ""),
+            "pmatrix" => ("(", ")", ""),
+            "bmatrix" => ("[", "]", ""),
+            "Bmatrix" => ("{", "}", ""),
+            "vmatrix" => ("|", "|", ""),
+            "Vmatrix" => ("‖", "‖", ""),
+            "cases" => ("{", "", "left"),
+            "aligned" | "align" | "align*" => ("", "", "right left"),
+            "gathered" => ("", "", ""),
+            _ => return self.error(at, format!("unknown environment `{}`", name)),
+        };
+
+        let mut rows = vec![];
+        let mut row = vec![];
+        loop {
+            row.push(Node::Row(self.parse_list()?));
+            match self.next() {
+                (Token::Amp, _) => (),
+                (Token::NewRow, _) => rows.push(std::mem::take(&mut row)),
+                (Token::Command(end), at) if end == "end" => {
+                    let end = self.parse_raw_group("end")?;
+                    if end != name {
+                        return self.error(
+                            at,
+                            format!("`\\begin{{{}}}` ended by `\\end{{{}}}`", name, end),
+                        );
+                    }
+                    break;
+                }
+                (_, at) => return self.error(at, format!("missing `\\end{{{}}}`", name)),
+            }
+        }
+        // A trailing `\\` doesn't start another row.
+        if !(row.len() == 1 && row[0] == Node::Row(vec![])) {
+            rows.push(row);
+        }
+
+        let table = Node::Table(rows, align);
+        if open.is_empty() && close.is_empty() {
+            Ok(table)
+        } else {
+            Ok(Node::Fenced(
+                open.to_string(),
+                vec![table],
+                close.to_string(),
+            ))
+        }
+    }
+}
+
+fn render_node(node: &Node, out: &mut String) {
+    match node {
+        Node::Ident(name, variant) => {
+            out.push_str("<mi");
+            if let Some(variant) = variant {
+                let _ = write!(out, " mathvariant=\"{}\"", variant);
+            }
+            let _ = write!(out, ">{}</mi>", escape(name));
+        }
+        Node::Number(number) => {
+            let _ = write!(out, "<mn>{}</mn>", number);
+        }
+        Node::Op(op) => {
+            let _ = write!(out, "<mo>{}</mo>", escape(op));
+        }
+        Node::Limits(op) => {
+            let _ = write!(out, "<mo movablelimits=\"true\">{}</mo>", escape(op));
+        }
+        Node::Delimiter(delimiter, size) => {
+            let _ = write!(
+                out,
+                "<mo minsize=\"{}\" maxsize=\"{}\">{}</mo>",
+                size,
+                size,
+                escape(delimiter)
+            );
+        }
+        Node::Text(text) => {
+            let _ = write!(
+                out,
+                "<mtext>{}</mtext>",
+                escape(text).replace(' ', "\u{a0}")
+            );
+        }
+        Node::Space(width) => {
+            let _ = write!(out, "<mspace width=\"{}\"/>", width);
+        }
+        Node::Row(nodes) if nodes.len() == 1 => render_node(&nodes[0], out),
+        Node::Row(nodes) => {
+            out.push_str("<mrow>");
+            for node in nodes {
+                render_node(node, out);
+            }
+            out.push_str("</mrow>");
+        }
+        Node::Frac(numerator, denominator, line) => {
+            out.push_str(if *line {
+                "<mfrac>"
+            } else {
+                "<mfrac linethickness=\"0\">"
+            });
+            render_node(numerator, out);
+            render_node(denominator, out);
+            out.push_str("</mfrac>");
+        }
+        Node::Sqrt(base, None) => {
+            out.push_str("<msqrt>");
+            render_node(base, out);
+            out.push_str("</msqrt>");
+        }
+        Node::Sqrt(base, Some(index)) => {
+            out.push_str("<mroot>");
+            render_node(base, out);
+            render_node(index, out);
+            out.push_str("</mroot>");
+        }
+        Node::Scripts(base, sub, sup) => {
+            render_scripts(["msub", "msup", "msubsup"], base, sub, sup, out)
+        }
+        Node::UnderOver(base, under, over) => {
+            render_scripts(["munder", "mover", "munderover"], base, under, over, out)
+        }
+        Node::Accent {
+            base,
+            accent,
+            stretchy,
+            under,
+            ..
+        } => {
+            let (tag, attribute) = match under {
+                true => ("munder", "accentunder"),
+                false => ("mover", "accent"),
+            };
+            let _ = write!(out, "<{} {}=\"true\">", tag, attribute);
+            render_node(base, out);
+            let _ = write!(
+                out,
+                "<mo stretchy=\"{}\">{}</mo></{}>",
+                stretchy,
+                escape(accent),
+                tag
+            );
+        }
+        Node::Fenced(open, nodes, close) => {
+            out.push_str("<mrow>");
+            if !open.is_empty() {
+                let _ = write!(out, "<mo fence=\"true\">{}</mo>", escape(open));
+            }
+            for node in nodes {
+                render_node(node, out);
+            }
+            if !close.is_empty() {
+                let _ = write!(out, "<mo fence=\"true\">{}</mo>", escape(close));
+            }
+            out.push_str("</mrow>");
+        }
+        Node::Table(rows, align) => {
+            out.push_str("<mtable");
+            if !align.is_empty() {
+                let _ = write!(out, " columnalign=\"{}\"", align);
+            }
+            out.push('>');
+            for row in rows {
+                out.push_str("<mtr>");
+                for cell in row {
+                    out.push_str("<mtd>");
+                    render_node(cell, out);
+                    out.push_str("</mtd>");
+                }
+                out.push_str("</mtr>");
+            }
+            out.push_str("</mtable>");
+        }
+    }
+}
+
+fn render_scripts(
+    [sub_tag, sup_tag, both_tag]: [&str; 3],
+    base: &Node,
+    sub: &Option<Box<Node>>,
+    sup: &Option<Box<Node>>,
+    out: &mut String,
+) {
+    let tag = match (sub, sup) {
+        (Some(_), Some(_)) => both_tag,
+        (Some(_), None) => sub_tag,
+        _ => sup_tag,
+    };
+    let _ = write!(out, "<{}>", tag);
+    render_node(base, out);
+    for script in sub.iter().chain(sup) {
+        render_node(script, out);
+    }
+    let _ = write!(out, "</{}>", tag);
+}
+
+/// Renders a LaTeX formula as MathML, keeping the formula around as an
+/// annotation.
+pub fn render(tex: &str, display: bool) -> Result<String> {
+    let mut parser = Parser { src: tex, pos: 0 };
+    let nodes = parser.parse_list()?;
+    match parser.next() {
+        (Token::End, _) => (),
+        (Token::Close, at) => return parser.error(at, "unexpected `}`"),
+        (Token::Amp, at) => return parser.error(at, "unexpected `&` outside of an environment"),
+        (Token::NewRow, at) => {
+            return parser.error(at, "unexpected `\\\\` outside of an environment")
+        }
+        (Token::Command(name), at) if name == "right" => {
+            return parser.error(at, "`\\right` without `\\left`")
+        }
+        (_, at) => return parser.error(at, "`\\end` without `\\begin`"),
+    }
+
+    let mut out = String::new();
+    out.push_str("<math xmlns=\"http://www.w3.org/1998/Math/MathML\"");
+    if display {
+        out.push_str(" display=\"block\"");
+    }
+    out.push_str("><semantics>");
+    render_node(&Node::Row(vec![Node::Row(nodes)]), &mut out);
+    let _ = write!(
+        out,
+        "<annotation encoding=\"application/x-tex\">{}</annotation></semantics></math>",
+        escape(tex.trim())
+    );
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract() {
+        let source = "Costs $5 and $10.\n\nBut $x_1 = y$.\n\n\
+                      `$a$` and \\$b$\n\n$$\n\\frac{1}{2}\n$$\n\n```\n$c$\n```\n";
+        let (out, formulas) = extract(source);
+        assert_eq!(
+            formulas,
+            vec![
+                Formula {
+                    tex: "x_1 = y".to_string(),
+                    display: false,
+                    line: 3,
+                },
+                Formula {
+                    tex: "\n\\frac{1}{2}\n".to_string(),
+                    display: true,
+                    line: 7,
+                },
+            ]
+        );
+        assert_eq!(
+            replace_placeholders(&out, &["x".to_string(), "y".to_string()]),
+            "Costs $5 and $10.\n\nBut x.\n\n`$a$` and \\$b$\n\ny\n\n```\n$c$\n```\n"
+        );
+    }
+
+    #[test]
+    fn test_formulas_in_text() {
+        let source = "Text $a$ and [$b$](http://x/$c$).\n\n    code $d$ here\n\n\
+                      <div>$e$</div>\n\n![$f$](g.png)\n";
+        let (raw, formulas) = extract(source);
+        let arena = comrak::Arena::new();
+        let mut opts = comrak::ComrakOptions::default();
+        opts.render.unsafe_ = true;
+        let root = comrak::parse_document(&arena, &raw, &opts);
+        assert_eq!(
+            formulas_in_text(root, &formulas),
+            vec![true, true, false, false, false, false]
+        );
+
+        let mut html = vec![];
+        comrak::format_html(root, &opts, &mut html).unwrap();
+        let rendered: Vec<String> = (0..formulas.len()).map(|i| format!("<m{}>", i)).collect();
+        assert_eq!(
+            replace_placeholders(&String::from_utf8_lossy(&html), &rendered),
+            "<p>Text <m0> and <a href=\"http://x/$c$\"><m1></a>.</p>\n\
+             <pre><code>code $d$ here\n</code></pre>\n\
+             <div>$e$</div>\n\
+             <p><img src=\"g.png\" alt=\"$f$\" /></p>\n"
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render("x^2 + \\frac{a}{\\beta}", false).unwrap(),
+            "<math xmlns=\"http://www.w3.org/1998/Math/MathML\"><semantics><mrow>\
+             <msup><mi>x</mi><mn>2</mn></msup><mo>+</mo>\
+             <mfrac><mi>a</mi><mi>β</mi></mfrac></mrow>\
+             <annotation encoding=\"application/x-tex\">x^2 + \\frac{a}{\\beta}</annotation>\
+             </semantics></math>"
+        );
+        assert!(render("\\sum_{i=1}^n i", true)
+            .unwrap()
+            .contains("<munderover><mo movablelimits=\"true\">∑</mo>"));
+    }
+
+    #[test]
+    fn test_render_errors() {
+        let error = |tex| render(tex, false).unwrap_err();
+        assert_eq!(error("\\frac{a}{b").message, "missing `}`");
+        assert_eq!(error("a\n+ \\foo").offset, 4);
+        assert_eq!(error("\\foo").message, "unknown command `\\foo`");
+        assert_eq!(
+            error("\\begin{pmatrix} a \\end{bmatrix}").message,
+            "`\\begin{pmatrix}` ended by `\\end{bmatrix}`"
+        );
+    }
+}
@@ -1,13 +1,20 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use crate::parser::{parse_sexp, SExpr};
 
 const SITEFILE_NAME: &str = "site";
+const PROJECT_FILE_NAME: &str = "hotstuff-project";
 
 #[derive(Debug, Clone, Default)]
 pub struct Project {
     root: PathBuf,
     output_dir: PathBuf,
+    drafts: bool,
+    future: bool,
+    base_url: Option<String>,
+    sitemap: bool,
+    robots: bool,
+    markdown: Markdown,
 }
 
 impl Project {
@@ -17,14 +24,42 @@ impl Project {
     pub fn root(self) -> PathBuf {
         self.root
     }
+    pub fn drafts(self) -> bool {
+        self.drafts
+    }
+    pub fn future(self) -> bool {
+        self.future
+    }
+    pub fn base_url(self) -> Option<String> {
+        self.base_url
+    }
+    pub fn sitemap(self) -> bool {
+        self.sitemap
+    }
+    pub fn robots(self) -> bool {
+        self.robots
+    }
+    pub fn markdown(self) -> Markdown {
+        self.markdown
+    }
 
     pub fn new() -> Project {
         Project {
             root: PathBuf::from("."),
             output_dir: PathBuf::from("./_public"),
+            drafts: false,
+            future: false,
+            base_url: None,
+            sitemap: false,
+            robots: false,
+            markdown: Markdown::default(),
         }
     }
 
+    pub fn file_name() -> String {
+        PROJECT_FILE_NAME.to_string()
+    }
+
     pub fn with_root(self, root: PathBuf) -> Project {
         Project { root, ..self }
     }
@@ -32,6 +67,596 @@ impl Project {
     pub fn with_output_dir(self, output_dir: PathBuf) -> Project {
         Project { output_dir, ..self }
     }
+
+    /// The URL an output file will be served from, relative to the root of
+    /// the output folder. `index.html` files are served from their folder.
+    pub fn url_for(&self, output: &Path) -> String {
+        let relative = output.strip_prefix(&self.output_dir).unwrap_or(output);
+        let url = format!("/{}", relative.to_string_lossy().replace('\\', "/"));
+        match url.strip_suffix("index.html") {
+            Some(url) if url.ends_with('/') => url.to_string(),
+            _ => url,
+        }
+    }
+
+    /// Include documents marked as drafts in the build.
+    pub fn with_drafts(self, drafts: bool) -> Project {
+        Project { drafts, ..self }
+    }
+
+    /// Include documents with a publish date in the future in the build.
+    pub fn with_future(self, future: bool) -> Project {
+        Project { future, ..self }
+    }
+
+    /// The URL the output folder will be published at, like
+    /// `https://example.com`, for the outputs that need absolute URLs.
+    pub fn with_base_url(self, base_url: String) -> Project {
+        let base_url = Some(base_url.trim_end_matches('/').to_string());
+        Project { base_url, ..self }
+    }
+
+    /// Write a `sitemap.xml` listing every page in the project.
+    pub fn with_sitemap(self, sitemap: bool) -> Project {
+        Project { sitemap, ..self }
+    }
+
+    /// Write a `robots.txt` pointing crawlers to the sitemap.
+    pub fn with_robots(self, robots: bool) -> Project {
+        Project { robots, ..self }
+    }
+
+    /// The Markdown options for every site that doesn't set its own.
+    pub fn with_markdown(self, markdown: Markdown) -> Project {
+        Project { markdown, ..self }
+    }
+
+    /// Reads the settings in the `hotstuff-project` file at the root of the
+    /// project, if there is one.
+    pub fn with_project_file(self) -> Project {
+        let path = self.root.join(Project::file_name());
+        let file = std::fs::read_to_string(path).unwrap_or_default();
+        let mut project = self;
+        for sexp in parse_sexp(&file) {
+            if let SExpr::List(sexp) = &sexp {
+                let flag = sexp
+                    .get(1)
+                    .is_none_or(|flag| atom_to_string(flag) == "true");
+                match (name_of(sexp).as_str(), sexp.get(1)) {
+                    ("base-url", Some(base_url)) => {
+                        project = project.with_base_url(atom_to_string(base_url))
+                    }
+                    ("sitemap", _) => project = project.with_sitemap(flag),
+                    ("robots", _) => project = project.with_robots(flag),
+                    ("markdown", _) => {
+                        project = project.with_markdown(Markdown::from_sexp(&sexp[1..]))
+                    }
+                    _ => (),
+                }
+            }
+        }
+        project
+    }
+
+    /// The absolute URL of an output file, when the project has a base URL.
+    pub fn absolute_url_for(&self, output: &Path) -> Option<String> {
+        let base_url = self.base_url.as_ref()?;
+        Some(format!("{}{}", base_url, self.url_for(output)))
+    }
+}
+
+fn atom_to_string(sexp: &SExpr) -> String {
+    if let SExpr::Atom(a) = sexp {
+        a.replace("\"", "")
+    } else {
+        "".to_string()
+    }
+}
+
+/// The name a directive starts with, or nothing for an empty one like `()`,
+/// which then matches no directive at all.
+fn name_of(list: &[SExpr]) -> String {
+    list.first().map(atom_to_string).unwrap_or_default()
+}
+
+fn atom_to_path(sexp: &SExpr) -> PathBuf {
+    PathBuf::from(atom_to_string(sexp).replace("./", ""))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortBy {
+    Date,
+    Title,
+    Weight,
+}
+
+/// A listing of all the documents in a site, rendered into an index page.
+#[derive(Debug, Clone)]
+pub struct Collection {
+    template: Option<PathBuf>,
+    output: PathBuf,
+    title: Option<String>,
+    sort_by: SortBy,
+    descending: bool,
+    paginate: Option<usize>,
+}
+
+impl Collection {
+    pub fn template(self) -> Option<PathBuf> {
+        self.template
+    }
+    pub fn output(self) -> PathBuf {
+        self.output
+    }
+    pub fn title(self) -> Option<String> {
+        self.title
+    }
+    pub fn sort_by(self) -> SortBy {
+        self.sort_by
+    }
+    pub fn descending(self) -> bool {
+        self.descending
+    }
+    pub fn paginate(self) -> Option<usize> {
+        self.paginate
+    }
+
+    fn from_sexp(fields: &[SExpr]) -> Collection {
+        let mut collection = Collection {
+            template: None,
+            output: PathBuf::from("index.html"),
+            title: None,
+            sort_by: SortBy::Date,
+            descending: true,
+            paginate: None,
+        };
+
+        for field in fields {
+            if let SExpr::List(field) = field {
+                let name = name_of(field);
+                let args = field.get(1..).unwrap_or_default();
+                match (name.as_str(), args.first()) {
+                    ("template", Some(template)) => {
+                        collection.template = Some(atom_to_path(template))
+                    }
+                    ("output", Some(output)) => collection.output = atom_to_path(output),
+                    ("title", Some(title)) => collection.title = Some(atom_to_string(title)),
+                    ("paginate", Some(per_page)) => {
+                        collection.paginate =
+                            atom_to_string(per_page).parse().ok().filter(|n| *n > 0)
+                    }
+                    ("sort-by", Some(sort_by)) => {
+                        collection.sort_by = match atom_to_string(sort_by).as_str() {
+                            "title" => SortBy::Title,
+                            "weight" => SortBy::Weight,
+                            _ => SortBy::Date,
+                        };
+                        // Newest first, but alphabetical and lightest first.
+                        collection.descending = match args.get(1).map(atom_to_string) {
+                            Some(order) => order == "desc",
+                            None => collection.sort_by == SortBy::Date,
+                        };
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        collection
+    }
+}
+
+/// A way of classifying documents, like `tags` or `categories`, by a front
+/// matter field. Every term gets a page listing its documents, and the
+/// taxonomy gets a page listing all of its terms.
+#[derive(Debug, Clone)]
+pub struct Taxonomy {
+    name: String,
+    template: Option<PathBuf>,
+    index_template: Option<PathBuf>,
+    paginate: Option<usize>,
+}
+
+impl Taxonomy {
+    pub fn name(self) -> String {
+        self.name
+    }
+    pub fn template(self) -> Option<PathBuf> {
+        self.template
+    }
+    pub fn index_template(self) -> Option<PathBuf> {
+        self.index_template
+    }
+    pub fn paginate(self) -> Option<usize> {
+        self.paginate
+    }
+
+    pub fn from_sexp(name: &SExpr, fields: &[SExpr]) -> Taxonomy {
+        let mut taxonomy = Taxonomy {
+            name: atom_to_string(name),
+            template: None,
+            index_template: None,
+            paginate: None,
+        };
+
+        for field in fields {
+            if let SExpr::List(field) = field {
+                match (name_of(field).as_str(), field.get(1)) {
+                    ("template", Some(template)) => {
+                        taxonomy.template = Some(atom_to_path(template))
+                    }
+                    ("index-template", Some(template)) => {
+                        taxonomy.index_template = Some(atom_to_path(template))
+                    }
+                    ("paginate", Some(per_page)) => {
+                        taxonomy.paginate = atom_to_string(per_page).parse().ok().filter(|n| *n > 0)
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        taxonomy
+    }
+}
+
+/// Pages listing the documents of a site by the year, and optionally the
+/// month, they were published in.
+#[derive(Debug, Clone)]
+pub struct Archive {
+    template: Option<PathBuf>,
+    monthly: bool,
+    paginate: Option<usize>,
+}
+
+impl Archive {
+    pub fn template(self) -> Option<PathBuf> {
+        self.template
+    }
+    pub fn monthly(self) -> bool {
+        self.monthly
+    }
+    pub fn paginate(self) -> Option<usize> {
+        self.paginate
+    }
+
+    pub fn from_sexp(fields: &[SExpr]) -> Archive {
+        let mut archive = Archive {
+            template: None,
+            monthly: false,
+            paginate: None,
+        };
+
+        for field in fields {
+            if let SExpr::List(field) = field {
+                match (name_of(field).as_str(), field.get(1)) {
+                    ("template", Some(template)) => archive.template = Some(atom_to_path(template)),
+                    ("monthly", flag) => {
+                        archive.monthly = flag.is_none_or(|flag| atom_to_string(flag) == "true")
+                    }
+                    ("paginate", Some(per_page)) => {
+                        archive.paginate = atom_to_string(per_page).parse().ok().filter(|n| *n > 0)
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        archive
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FeedFormat {
+    Rss,
+    Atom,
+    Json,
+}
+
+/// An RSS, Atom, or JSON feed of the latest documents of a site.
+#[derive(Debug, Clone)]
+pub struct Feed {
+    title: Option<String>,
+    outputs: Vec<(FeedFormat, PathBuf)>,
+    full_content: bool,
+    limit: usize,
+}
+
+impl Feed {
+    pub fn title(self) -> Option<String> {
+        self.title
+    }
+    pub fn outputs(self) -> Vec<(FeedFormat, PathBuf)> {
+        self.outputs
+    }
+    pub fn full_content(self) -> bool {
+        self.full_content
+    }
+    pub fn limit(self) -> usize {
+        self.limit
+    }
+
+    fn from_sexp(fields: &[SExpr]) -> Feed {
+        let mut feed = Feed {
+            title: None,
+            outputs: vec![],
+            full_content: false,
+            limit: 20,
+        };
+
+        for field in fields {
+            if let SExpr::List(field) = field {
+                match (name_of(field).as_str(), field.get(1)) {
+                    ("title", Some(title)) => feed.title = Some(atom_to_string(title)),
+                    ("rss", output) => feed.outputs.push((
+                        FeedFormat::Rss,
+                        output.map_or_else(|| PathBuf::from("rss.xml"), atom_to_path),
+                    )),
+                    ("atom", output) => feed.outputs.push((
+                        FeedFormat::Atom,
+                        output.map_or_else(|| PathBuf::from("atom.xml"), atom_to_path),
+                    )),
+                    ("json", output) => feed.outputs.push((
+                        FeedFormat::Json,
+                        output.map_or_else(|| PathBuf::from("feed.json"), atom_to_path),
+                    )),
+                    ("content", Some(content)) => {
+                        feed.full_content = atom_to_string(content) == "full"
+                    }
+                    ("limit", Some(limit)) => {
+                        if let Ok(limit) = atom_to_string(limit).parse() {
+                            feed.limit = limit
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        if feed.outputs.is_empty() {
+            feed.outputs = vec![
+                (FeedFormat::Rss, PathBuf::from("rss.xml")),
+                (FeedFormat::Atom, PathBuf::from("atom.xml")),
+                (FeedFormat::Json, PathBuf::from("feed.json")),
+            ];
+        }
+
+        feed
+    }
+}
+
+/// The options Markdown documents are compiled with. Options that are not
+/// set are taken from the project, and then from our defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Markdown {
+    strikethrough: Option<bool>,
+    tagfilter: Option<bool>,
+    table: Option<bool>,
+    autolink: Option<bool>,
+    tasklist: Option<bool>,
+    superscript: Option<bool>,
+    header_ids: Option<Option<String>>,
+    footnotes: Option<bool>,
+    description_lists: Option<bool>,
+    smart: Option<bool>,
+    default_info_string: Option<Option<String>>,
+    hardbreaks: Option<bool>,
+    github_pre_lang: Option<bool>,
+    width: Option<usize>,
+    unsafe_: Option<bool>,
+    escape: Option<bool>,
+    math: Option<bool>,
+}
+
+impl Markdown {
+    pub fn from_sexp(fields: &[SExpr]) -> Markdown {
+        let mut markdown = Markdown::default();
+
+        for field in fields {
+            if let SExpr::List(field) = field {
+                let value = field.get(1).map(atom_to_string);
+                let flag = value.as_ref().is_none_or(|flag| flag == "true");
+                // Options that take text can also be turned off with `false`.
+                let text = || value.clone().filter(|text| text != "false");
+                match name_of(field).as_str() {
+                    "strikethrough" => markdown.strikethrough = Some(flag),
+                    "tagfilter" => markdown.tagfilter = Some(flag),
+                    "table" => markdown.table = Some(flag),
+                    "autolink" => markdown.autolink = Some(flag),
+                    "tasklist" => markdown.tasklist = Some(flag),
+                    "superscript" => markdown.superscript = Some(flag),
+                    // A bare `(header-ids)` turns them on without a prefix.
+                    "header-ids" => {
+                        markdown.header_ids = Some(match value.as_deref() {
+                            None | Some("true") => Some("".to_string()),
+                            _ => text(),
+                        })
+                    }
+                    "footnotes" => markdown.footnotes = Some(flag),
+                    "description-lists" => markdown.description_lists = Some(flag),
+                    "smart" => markdown.smart = Some(flag),
+                    "default-info-string" => markdown.default_info_string = Some(text()),
+                    "hardbreaks" => markdown.hardbreaks = Some(flag),
+                    "github-pre-lang" => markdown.github_pre_lang = Some(flag),
+                    "width" => markdown.width = value.and_then(|width| width.parse().ok()),
+                    "unsafe" => markdown.unsafe_ = Some(flag),
+                    "escape" => markdown.escape = Some(flag),
+                    "math" => markdown.math = Some(flag),
+                    _ => (),
+                }
+            }
+        }
+
+        markdown
+    }
+
+    /// Fills in the options that are not set here with the ones in `defaults`.
+    pub fn or(self, defaults: Markdown) -> Markdown {
+        Markdown {
+            strikethrough: self.strikethrough.or(defaults.strikethrough),
+            tagfilter: self.tagfilter.or(defaults.tagfilter),
+            table: self.table.or(defaults.table),
+            autolink: self.autolink.or(defaults.autolink),
+            tasklist: self.tasklist.or(defaults.tasklist),
+            superscript: self.superscript.or(defaults.superscript),
+            header_ids: self.header_ids.or(defaults.header_ids),
+            footnotes: self.footnotes.or(defaults.footnotes),
+            description_lists: self.description_lists.or(defaults.description_lists),
+            smart: self.smart.or(defaults.smart),
+            default_info_string: self.default_info_string.or(defaults.default_info_string),
+            hardbreaks: self.hardbreaks.or(defaults.hardbreaks),
+            github_pre_lang: self.github_pre_lang.or(defaults.github_pre_lang),
+            width: self.width.or(defaults.width),
+            unsafe_: self.unsafe_.or(defaults.unsafe_),
+            escape: self.escape.or(defaults.escape),
+            math: self.math.or(defaults.math),
+        }
+    }
+
+    /// Whether `$...$` and `$$...$$` formulas are rendered as MathML.
+    pub fn math(&self) -> bool {
+        self.math.unwrap_or(false)
+    }
+
+    pub fn comrak_options(&self) -> comrak::ComrakOptions {
+        let mut opts = comrak::ComrakOptions::default();
+        opts.extension.strikethrough = self.strikethrough.unwrap_or(false);
+        opts.extension.tagfilter = self.tagfilter.unwrap_or(false);
+        opts.extension.table = self.table.unwrap_or(false);
+        opts.extension.autolink = self.autolink.unwrap_or(true);
+        opts.extension.tasklist = self.tasklist.unwrap_or(false);
+        opts.extension.superscript = self.superscript.unwrap_or(false);
+        opts.extension.header_ids = self.header_ids.clone().unwrap_or(Some("".to_string()));
+        opts.extension.footnotes = self.footnotes.unwrap_or(true);
+        opts.extension.description_lists = self.description_lists.unwrap_or(false);
+        opts.parse.smart = self.smart.unwrap_or(true);
+        opts.parse.default_info_string = self.default_info_string.clone().unwrap_or(None);
+        opts.render.hardbreaks = self.hardbreaks.unwrap_or(false);
+        opts.render.github_pre_lang = self.github_pre_lang.unwrap_or(false);
+        opts.render.width = self.width.unwrap_or(0);
+        opts.render.unsafe_ = self.unsafe_.unwrap_or(true);
+        opts.render.escape = self.escape.unwrap_or(false);
+        opts
+    }
+}
+
+/// Which headings of a document go in its table of contents.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Toc {
+    min_depth: usize,
+    max_depth: usize,
+}
+
+impl Toc {
+    pub fn min_depth(self) -> usize {
+        self.min_depth
+    }
+    pub fn max_depth(self) -> usize {
+        self.max_depth
+    }
+
+    pub fn from_sexp(fields: &[SExpr]) -> Toc {
+        let mut toc = Toc {
+            min_depth: 2,
+            max_depth: 3,
+        };
+
+        for field in fields {
+            if let SExpr::List(field) = field {
+                let depth = field
+                    .get(1)
+                    .and_then(|depth| atom_to_string(depth).parse().ok())
+                    .filter(|depth| (1..=6).contains(depth));
+                match (name_of(field).as_str(), depth) {
+                    ("min-depth", Some(depth)) => toc.min_depth = depth,
+                    ("max-depth", Some(depth)) => toc.max_depth = depth,
+                    _ => (),
+                }
+            }
+        }
+
+        toc
+    }
+}
+
+/// How the code blocks in the documents of a site get highlighted. Colors
+/// are either inlined, or left to a stylesheet through CSS classes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Highlight {
+    theme: String,
+    classes: bool,
+    line_numbers: bool,
+}
+
+impl Highlight {
+    pub fn theme(self) -> String {
+        self.theme
+    }
+    pub fn classes(self) -> bool {
+        self.classes
+    }
+    pub fn line_numbers(self) -> bool {
+        self.line_numbers
+    }
+
+    pub fn from_sexp(fields: &[SExpr]) -> Highlight {
+        let mut highlight = Highlight {
+            theme: "base16-ocean.dark".to_string(),
+            classes: false,
+            line_numbers: false,
+        };
+
+        for field in fields {
+            if let SExpr::List(field) = field {
+                let flag = field
+                    .get(1)
+                    .is_none_or(|flag| atom_to_string(flag) == "true");
+                match (name_of(field).as_str(), field.get(1)) {
+                    ("theme", Some(theme)) => highlight.theme = atom_to_string(theme),
+                    ("classes", _) => highlight.classes = flag,
+                    ("line-numbers", _) => highlight.line_numbers = flag,
+                    _ => (),
+                }
+            }
+        }
+
+        highlight
+    }
+}
+
+/// A search index of the documents of a site, and the widget to search it.
+#[derive(Debug, Clone)]
+pub struct Search {
+    index: PathBuf,
+    script: PathBuf,
+}
+
+impl Search {
+    pub fn index(self) -> PathBuf {
+        self.index
+    }
+    pub fn script(self) -> PathBuf {
+        self.script
+    }
+
+    fn from_sexp(fields: &[SExpr]) -> Search {
+        let mut search = Search {
+            index: PathBuf::from("search.json"),
+            script: PathBuf::from("search.js"),
+        };
+
+        for field in fields {
+            if let SExpr::List(field) = field {
+                match (name_of(field).as_str(), field.get(1)) {
+                    ("index", Some(index)) => search.index = atom_to_path(index),
+                    ("script", Some(script)) => search.script = atom_to_path(script),
+                    _ => (),
+                }
+            }
+        }
+
+        search
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -39,6 +664,17 @@ pub struct Sitefile {
     dir: PathBuf,
     template: Option<PathBuf>,
     assets: Option<Vec<PathBuf>>,
+    recursive: bool,
+    permalink: Option<String>,
+    collection: Option<Collection>,
+    taxonomies: Vec<Taxonomy>,
+    archive: Option<Archive>,
+    feed: Option<Feed>,
+    content_index: Option<PathBuf>,
+    search: Option<Search>,
+    highlight: Option<Highlight>,
+    markdown: Markdown,
+    toc: Toc,
 }
 
 impl Sitefile {
@@ -51,6 +687,39 @@ impl Sitefile {
     pub fn template(self) -> Option<PathBuf> {
         self.template
     }
+    pub fn recursive(self) -> bool {
+        self.recursive
+    }
+    pub fn permalink(self) -> Option<String> {
+        self.permalink
+    }
+    pub fn collection(self) -> Option<Collection> {
+        self.collection
+    }
+    pub fn taxonomies(self) -> Vec<Taxonomy> {
+        self.taxonomies
+    }
+    pub fn archive(self) -> Option<Archive> {
+        self.archive
+    }
+    pub fn feed(self) -> Option<Feed> {
+        self.feed
+    }
+    pub fn content_index(self) -> Option<PathBuf> {
+        self.content_index
+    }
+    pub fn search(self) -> Option<Search> {
+        self.search
+    }
+    pub fn highlight(self) -> Option<Highlight> {
+        self.highlight
+    }
+    pub fn markdown(self) -> Markdown {
+        self.markdown
+    }
+    pub fn toc(self) -> Toc {
+        self.toc
+    }
 
     pub fn name() -> String {
         SITEFILE_NAME.to_string()
@@ -63,11 +732,25 @@ impl Sitefile {
                 dir: root,
                 template: None,
                 assets: None,
+                recursive: false,
+                permalink: None,
+                collection: None,
+                taxonomies: vec![],
+                archive: None,
+                feed: None,
+                content_index: None,
+                search: None,
+                highlight: None,
+                markdown: Markdown::default(),
+                toc: Toc::from_sexp(&[]),
             };
 
             for sexp in parse_sexp(&file) {
                 if let SExpr::List(sexp) = &sexp {
-                    let name = sexp[0].clone();
+                    let name = match sexp.first() {
+                        Some(name) => name.clone(),
+                        None => continue,
+                    };
                     if name == SExpr::Atom("assets".to_string()) {
                         sitefile.assets = Some(
                             sexp[1..]
@@ -96,6 +779,60 @@ impl Sitefile {
                         .replace("./", "");
                         sitefile.template = Some(PathBuf::from(template_name));
                     }
+
+                    if name == SExpr::Atom("recursive".to_string()) {
+                        sitefile.recursive = match sexp.get(1) {
+                            Some(SExpr::Atom(flag)) => flag == "true",
+                            _ => true,
+                        };
+                    }
+
+                    if name == SExpr::Atom("permalink".to_string()) {
+                        if let Some(SExpr::Atom(permalink)) = sexp.get(1) {
+                            sitefile.permalink = Some(permalink.replace("\"", ""));
+                        }
+                    }
+
+                    if name == SExpr::Atom("collection".to_string()) {
+                        sitefile.collection = Some(Collection::from_sexp(&sexp[1..]));
+                    }
+
+                    if name == SExpr::Atom("content-index".to_string()) {
+                        sitefile.content_index = Some(
+                            sexp.get(1)
+                                .map_or_else(|| PathBuf::from("content.json"), atom_to_path),
+                        );
+                    }
+
+                    if name == SExpr::Atom("toc".to_string()) {
+                        sitefile.toc = Toc::from_sexp(&sexp[1..]);
+                    }
+
+                    if name == SExpr::Atom("markdown".to_string()) {
+                        sitefile.markdown = Markdown::from_sexp(&sexp[1..]);
+                    }
+
+                    if name == SExpr::Atom("highlight".to_string()) {
+                        sitefile.highlight = Some(Highlight::from_sexp(&sexp[1..]));
+                    }
+
+                    if name == SExpr::Atom("search".to_string()) {
+                        sitefile.search = Some(Search::from_sexp(&sexp[1..]));
+                    }
+
+                    if name == SExpr::Atom("feed".to_string()) {
+                        sitefile.feed = Some(Feed::from_sexp(&sexp[1..]));
+                    }
+
+                    if name == SExpr::Atom("archive".to_string()) {
+                        sitefile.archive = Some(Archive::from_sexp(&sexp[1..]));
+                    }
+
+                    if name == SExpr::Atom("taxonomy".to_string()) && sexp.len() > 1 {
+                        sitefile
+                            .taxonomies
+                            .push(Taxonomy::from_sexp(&sexp[1], &sexp[2..]));
+                    }
                 }
             }
 
@@ -105,3 +842,70 @@ impl Sitefile {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_markdown_header_ids() {
+        let header_ids = |fields: &[&str]| {
+            let field = fields
+                .iter()
+                .map(|atom| SExpr::Atom(atom.to_string()))
+                .collect();
+            Markdown::from_sexp(&[SExpr::List(field)])
+                .comrak_options()
+                .extension
+                .header_ids
+        };
+        assert_eq!(header_ids(&["header-ids"]), Some("".to_string()));
+        assert_eq!(header_ids(&["header-ids", "true"]), Some("".to_string()));
+        assert_eq!(
+            header_ids(&["header-ids", "\"h-\""]),
+            Some("h-".to_string())
+        );
+        assert_eq!(header_ids(&["header-ids", "false"]), None);
+    }
+
+    #[test]
+    fn test_markdown_empty_field() {
+        let markdown = Markdown::from_sexp(&[
+            SExpr::List(vec![]),
+            SExpr::List(vec![SExpr::Atom("smart".to_string())]),
+        ]);
+        assert_eq!(
+            markdown,
+            Markdown {
+                smart: Some(true),
+                ..Markdown::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_empty_directives() {
+        let root = std::env::temp_dir().join(format!("hotstuff-model-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(
+            root.join(Project::file_name()),
+            "()\n(drafts ())\n(base-url \"https://example.com\")\n",
+        )
+        .unwrap();
+        std::fs::write(
+            root.join(Sitefile::name()),
+            "()\n(collection ())\n(taxonomy tags ())\n(archive ())\n(feed ())\n\
+             (toc ())\n(highlight ())\n(search ())\n",
+        )
+        .unwrap();
+        let project = Project::new().with_root(root.clone()).with_project_file();
+        let sitefile = Sitefile::from_dir_path(root.clone());
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(project.base_url(), Some("https://example.com".to_string()));
+        let sitefile = sitefile.unwrap();
+        assert!(sitefile.clone().collection().is_some());
+        assert!(sitefile.clone().feed().is_some());
+        assert_eq!(sitefile.taxonomies().len(), 1);
+    }
+}
@@ -4,8 +4,9 @@ pub enum SExpr {
     List(Vec<SExpr>),
 }
 
-pub(self) mod parsers {
+mod parsers {
     use crate::parser::SExpr;
+    use nom::branch::alt;
     use nom::character::complete::char;
     use nom::character::complete::multispace0;
     use nom::combinator::map;
@@ -14,23 +15,43 @@ pub(self) mod parsers {
     use nom::sequence::preceded;
     use nom::sequence::terminated;
 
+    /// Quoted strings may have whitespace and parens in them. They are kept
+    /// as atoms with their quotes, just like unquoted ones.
+    pub fn string(i: &str) -> nom::IResult<&str, SExpr> {
+        map(
+            delimited(
+                char('"'),
+                nom::bytes::complete::take_till(|c| c == '"'),
+                char('"'),
+            ),
+            |string: &str| SExpr::Atom(format!("\"{}\"", string)),
+        )(i)
+    }
+
     pub fn atom(i: &str) -> nom::IResult<&str, SExpr> {
-        map(nom::bytes::complete::is_not("( \t\n)"), |atom: &str| {
-            SExpr::Atom(atom.to_string())
-        })(i)
+        alt((
+            string,
+            map(nom::bytes::complete::is_not("( \t\n)"), |atom: &str| {
+                SExpr::Atom(atom.to_string())
+            }),
+        ))(i)
+    }
+
+    pub fn expr(i: &str) -> nom::IResult<&str, SExpr> {
+        alt((list, atom))(i)
     }
 
-    pub fn atoms(i: &str) -> nom::IResult<&str, SExpr> {
-        map(nom::multi::many0(preceded(multispace0, atom)), |atoms| {
-            SExpr::List(atoms)
+    pub fn exprs(i: &str) -> nom::IResult<&str, SExpr> {
+        map(nom::multi::many0(preceded(multispace0, expr)), |exprs| {
+            SExpr::List(exprs)
         })(i)
     }
 
     pub fn list(i: &str) -> nom::IResult<&str, SExpr> {
         delimited(
             context("opening paren", char('(')),
-            atoms,
-            context("opening paren", char(')')),
+            terminated(exprs, multispace0),
+            context("closing paren", char(')')),
         )(i)
     }
 
@@ -88,9 +109,30 @@ pub(self) mod parsers {
         }
 
         #[test]
-        fn test_atoms() {
+        fn test_nested_list() {
+            assert_eq!(
+                list("(collection\n  (sort-by date)\n  (title \"My (old) blog\") )"),
+                Ok((
+                    "",
+                    SExpr::List(vec![
+                        SExpr::Atom("collection".to_string()),
+                        SExpr::List(vec![
+                            SExpr::Atom("sort-by".to_string()),
+                            SExpr::Atom("date".to_string()),
+                        ]),
+                        SExpr::List(vec![
+                            SExpr::Atom("title".to_string()),
+                            SExpr::Atom("\"My (old) blog\"".to_string()),
+                        ]),
+                    ])
+                ))
+            );
+        }
+
+        #[test]
+        fn test_exprs() {
             assert_eq!(
-                atoms("assets ./abcd.css"),
+                exprs("assets ./abcd.css"),
                 Ok((
                     "",
                     SExpr::List(vec![
@@ -100,7 +142,7 @@ pub(self) mod parsers {
                 ))
             );
             assert_eq!(
-                atoms("assets ./abcd.css logo.png"),
+                exprs("assets ./abcd.css logo.png"),
                 Ok((
                     "",
                     SExpr::List(vec![
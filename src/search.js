@@ -0,0 +1,222 @@
+// hotstuff search widget.
+//
+// Include it in a template next to a search box and a list for the results:
+//
+//   <input type="search" id="hotstuff-search">
+//   <ul id="hotstuff-search-results"></ul>
+//   <script src="/docs/search.js" data-index="/docs/search.json"></script>
+//
+// Words in the index are stemmed, and so are query words, which match the
+// indexed words they start with, or that start with them.
+(function () {
+  var script = document.currentScript;
+  var indexUrl = script.getAttribute("data-index") || "search.json";
+  var input = document.getElementById(script.getAttribute("data-input") || "hotstuff-search");
+  var results = document.getElementById(script.getAttribute("data-results") || "hotstuff-search-results");
+  if (!input || !results) return;
+
+  var loading = null;
+  function load() {
+    if (!loading) {
+      loading = fetch(indexUrl).then(function (res) { return res.json(); });
+    }
+    return loading;
+  }
+
+  // Same as the ones left out of the index.
+  var stopWords = [
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into", "is", "it",
+    "no", "not", "of", "on", "or", "such", "that", "the", "their", "then", "there", "these",
+    "they", "this", "to", "was", "will", "with"
+  ];
+
+  // The English (Porter2) stemmer from Snowball, which the index was built
+  // with, so that words are stemmed the same way on both ends.
+  var stem = (function () {
+    var exceptions = {
+      skis: "ski", skies: "sky", dying: "die", lying: "lie", tying: "tie",
+      idly: "idl", gently: "gentl", ugly: "ugli", early: "earli", only: "onli",
+      singly: "singl", sky: "sky", news: "news", howe: "howe", atlas: "atlas",
+      cosmos: "cosmos", bias: "bias", andes: "andes"
+    };
+    var invariants = ["inning", "outing", "canning", "herring", "earring", "proceed", "exceed", "succeed"];
+    var step2 = {
+      tional: "tion", enci: "ence", anci: "ance", abli: "able", entli: "ent", izer: "ize",
+      ization: "ize", ational: "ate", ation: "ate", ator: "ate", alism: "al", aliti: "al",
+      alli: "al", fulness: "ful", ousli: "ous", ousness: "ous", iveness: "ive", iviti: "ive",
+      biliti: "ble", bli: "ble", ogi: "og", fulli: "ful", lessli: "less", li: ""
+    };
+    var step3 = {
+      tional: "tion", ational: "ate", alize: "al", icate: "ic", iciti: "ic", ical: "ic",
+      ful: "", ness: "", ative: ""
+    };
+    var step4 = [
+      "al", "ance", "ence", "er", "ic", "able", "ible", "ant", "ement", "ment", "ent", "ism",
+      "ate", "iti", "ous", "ive", "ize", "ion"
+    ];
+
+    function vowel(word, i) {
+      return i >= 0 && i < word.length && "aeiouy".indexOf(word[i]) !== -1;
+    }
+
+    function endsWith(word, suffix) {
+      return word.slice(word.length - suffix.length) === suffix;
+    }
+
+    // The longest of the suffixes the word ends with.
+    function longest(word, suffixes) {
+      return suffixes.filter(function (suffix) { return endsWith(word, suffix); })
+        .sort(function (a, b) { return b.length - a.length; })[0];
+    }
+
+    // Where the region after the first non-vowel following a vowel starts.
+    function region(word, from) {
+      var i = from;
+      while (i < word.length && !vowel(word, i)) i++;
+      while (i < word.length && vowel(word, i)) i++;
+      return Math.min(i + 1, word.length);
+    }
+
+    // Whether the word up to `end` ends in a short syllable.
+    function shortSyllable(word, end) {
+      var i = end - 1;
+      if (i >= 2 && !vowel(word, i) && "wxY".indexOf(word[i]) === -1 &&
+          vowel(word, i - 1) && !vowel(word, i - 2)) return true;
+      return i === 1 && !vowel(word, 1) && vowel(word, 0);
+    }
+
+    return function (word) {
+      if (exceptions[word]) return exceptions[word];
+      if (word.length < 3) return word;
+
+      word = word.replace(/^y/, "Y").replace(/([aeiouy])y/g, "$1Y");
+      var prefix = ["gener", "commun", "arsen"].filter(function (p) {
+        return word.indexOf(p) === 0;
+      })[0];
+      var p1 = prefix ? prefix.length : region(word, 0);
+      var p2 = region(word, p1);
+      var suffix;
+
+      // Step 1a: plurals.
+      suffix = longest(word, ["sses", "ied", "ies", "us", "ss", "s"]);
+      if (suffix === "sses") {
+        word = word.slice(0, -2);
+      } else if (suffix === "ied" || suffix === "ies") {
+        word = word.slice(0, word.length > 4 ? -2 : -1);
+      } else if (suffix === "s" && /[aeiouy]/.test(word.slice(0, -2))) {
+        word = word.slice(0, -1);
+      }
+
+      if (invariants.indexOf(word) === -1) {
+        // Step 1b: past tenses and gerunds.
+        suffix = longest(word, ["eed", "eedly", "ed", "edly", "ing", "ingly"]);
+        if (suffix === "eed" || suffix === "eedly") {
+          if (word.length - suffix.length >= p1) {
+            word = word.slice(0, -suffix.length) + "ee";
+          }
+        } else if (suffix && /[aeiouy]/.test(word.slice(0, -suffix.length))) {
+          word = word.slice(0, -suffix.length);
+          if (longest(word, ["at", "bl", "iz"])) {
+            word += "e";
+          } else if (/(bb|dd|ff|gg|mm|nn|pp|rr|tt)$/.test(word)) {
+            word = word.slice(0, -1);
+          } else if (p1 === word.length && shortSyllable(word, word.length)) {
+            word += "e";
+          }
+        }
+
+        // Step 1c: a final y after a consonant.
+        if (word.length > 2 && /[yY]$/.test(word) && !vowel(word, word.length - 2)) {
+          word = word.slice(0, -1) + "i";
+        }
+
+        // Steps 2 and 3: suffixes that become shorter ones.
+        suffix = longest(word, Object.keys(step2));
+        if (suffix && word.length - suffix.length >= p1) {
+          var before = word[word.length - suffix.length - 1];
+          if (suffix === "ogi" ? before === "l" : suffix !== "li" || "cdeghkmnrt".indexOf(before) !== -1) {
+            word = word.slice(0, -suffix.length) + step2[suffix];
+          }
+        }
+        suffix = longest(word, Object.keys(step3));
+        if (suffix && word.length - suffix.length >= (suffix === "ative" ? p2 : p1)) {
+          word = word.slice(0, -suffix.length) + step3[suffix];
+        }
+
+        // Step 4: suffixes that go away.
+        suffix = longest(word, step4);
+        if (suffix && word.length - suffix.length >= p2 &&
+            (suffix !== "ion" || /[st]$/.test(word.slice(0, -3)))) {
+          word = word.slice(0, -suffix.length);
+        }
+
+        // Step 5: a final e or l.
+        var last = word.length - 1;
+        if (word[last] === "e" && (last >= p2 || (last >= p1 && !shortSyllable(word, last)))) {
+          word = word.slice(0, -1);
+        } else if (word[last] === "l" && last >= p2 && word[last - 1] === "l") {
+          word = word.slice(0, -1);
+        }
+      }
+
+      return word.replace(/Y/g, "y");
+    };
+  })();
+
+  function tokenize(text) {
+    return text.toLowerCase().split(/[^\p{L}\p{N}]+/u).filter(function (word) {
+      return word.length > 1 && stopWords.indexOf(word) === -1;
+    }).map(stem);
+  }
+
+  function search(data, query) {
+    var words = tokenize(query);
+    if (words.length === 0) return [];
+    var terms = Object.keys(data.index);
+    var scores = null;
+    words.forEach(function (word) {
+      var found = {};
+      terms.forEach(function (term) {
+        if (term.indexOf(word) === 0 || (term.length > 2 && word.indexOf(term) === 0)) {
+          data.index[term].forEach(function (hit) {
+            found[hit[0]] = (found[hit[0]] || 0) + hit[1];
+          });
+        }
+      });
+      if (scores === null) {
+        scores = found;
+      } else {
+        Object.keys(scores).forEach(function (id) {
+          if (found[id] === undefined) delete scores[id];
+          else scores[id] += found[id];
+        });
+      }
+    });
+    return Object.keys(scores)
+      .sort(function (a, b) { return scores[b] - scores[a]; })
+      .map(function (id) { return data.documents[id]; });
+  }
+
+  function render(documents) {
+    results.innerHTML = "";
+    documents.slice(0, 20).forEach(function (doc) {
+      var item = document.createElement("li");
+      var link = document.createElement("a");
+      link.href = doc.url;
+      link.textContent = doc.title;
+      var snippet = document.createElement("p");
+      snippet.textContent = doc.snippet;
+      item.appendChild(link);
+      item.appendChild(snippet);
+      results.appendChild(item);
+    });
+  }
+
+  input.addEventListener("focus", load);
+  input.addEventListener("input", function () {
+    var query = input.value;
+    load().then(function (data) {
+      if (input.value === query) render(search(data, query));
+    });
+  });
+})();
@@ -0,0 +1,145 @@
+use nipper::Document as Html;
+use rust_stemmers::{Algorithm, Stemmer};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// The search widget templates can include to search a site.
+pub const SCRIPT: &str = include_str!("search.js");
+
+/// How much more a word in the title of a document counts than one in its
+/// text.
+const TITLE_WEIGHT: usize = 5;
+
+const SNIPPET_LENGTH: usize = 160;
+
+const STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into", "is", "it",
+    "no", "not", "of", "on", "or", "such", "that", "the", "their", "then", "there", "these",
+    "they", "this", "to", "was", "will", "with",
+];
+
+/// A document as it appears in a search index. Its text is read when the
+/// index is written, so only where to find it is kept here.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct SearchDocument {
+    pub input: PathBuf,
+    pub title: String,
+    pub url: String,
+    pub links: BTreeMap<String, String>,
+    pub wiki_links: BTreeMap<String, String>,
+}
+
+// Indexes cover whole sites, so we only show where documents come from.
+impl std::fmt::Debug for SearchDocument {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.input)
+    }
+}
+
+/// Splits text into lowercase, stemmed words, leaving out the ones too
+/// common to search for.
+pub fn tokenize(text: &str) -> Vec<String> {
+    let stemmer = Stemmer::create(Algorithm::English);
+    text.split(|c: char| !c.is_alphanumeric())
+        .map(str::to_lowercase)
+        .filter(|word| word.chars().count() > 1 && !STOP_WORDS.contains(&word.as_str()))
+        .map(|word| stemmer.stem(&word).to_string())
+        .collect()
+}
+
+/// The text of a compiled document, without its markup or its title, which
+/// is indexed on its own.
+pub fn text_of(html: &str) -> String {
+    let html = Html::from(html);
+    html.select("h1").remove();
+    html.select("body")
+        .text()
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+fn snippet(text: &str) -> String {
+    if text.chars().count() <= SNIPPET_LENGTH {
+        return text.to_string();
+    }
+    let cut: String = text.chars().take(SNIPPET_LENGTH).collect();
+    match cut.rfind(' ') {
+        Some(end) => format!("{}…", &cut[..end]),
+        None => format!("{}…", cut),
+    }
+}
+
+/// Builds an inverted index from every word to the documents it appears in,
+/// along with how many times it does, out of the text of every document.
+pub fn build_index(documents: &[(&SearchDocument, String)]) -> serde_json::Value {
+    let mut index: BTreeMap<String, BTreeMap<usize, usize>> = BTreeMap::new();
+    for (id, (document, text)) in documents.iter().enumerate() {
+        let title = tokenize(&document.title)
+            .into_iter()
+            .map(|word| (word, TITLE_WEIGHT));
+        let body = tokenize(text).into_iter().map(|word| (word, 1));
+        for (word, weight) in title.chain(body) {
+            *index.entry(word).or_default().entry(id).or_default() += weight;
+        }
+    }
+
+    serde_json::json!({
+        "documents": documents
+            .iter()
+            .map(|(document, text)| serde_json::json!({
+                "title": document.title,
+                "url": document.url,
+                "snippet": snippet(text),
+            }))
+            .collect::<Vec<serde_json::Value>>(),
+        "index": index
+            .into_iter()
+            .map(|(word, counts)| {
+                let counts: Vec<[usize; 2]> = counts.into_iter().map(|(id, n)| [id, n]).collect();
+                (word, serde_json::json!(counts))
+            })
+            .collect::<serde_json::Map<String, serde_json::Value>>(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize("The Running of the BULLS, in 2019!"),
+            vec!["run", "bull", "2019"]
+        );
+    }
+
+    #[test]
+    fn test_build_index() {
+        let document = |title: &str| SearchDocument {
+            input: PathBuf::from(format!("{}.md", title)),
+            title: title.to_string(),
+            url: format!("/{}.html", title),
+            links: BTreeMap::new(),
+            wiki_links: BTreeMap::new(),
+        };
+        let (studies, running) = (document("Studies"), document("Running"));
+        let index = build_index(&[
+            (&studies, "A study of running.".to_string()),
+            (&running, "Run, run!".to_string()),
+        ]);
+
+        assert_eq!(index["documents"][1]["url"], "/Running.html");
+        assert_eq!(index["documents"][1]["snippet"], "Run, run!");
+        assert_eq!(
+            index["index"]["studi"],
+            serde_json::json!([[0, TITLE_WEIGHT + 1]])
+        );
+        assert_eq!(
+            index["index"]["run"],
+            serde_json::json!([[0, 1], [1, TITLE_WEIGHT + 2]])
+        );
+        assert!(index["index"].get("study").is_none());
+    }
+}
@@ -0,0 +1,38 @@
+use crate::html::escape;
+
+/// A page as it appears in a sitemap.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct SitemapUrl {
+    pub loc: String,
+    pub lastmod: Option<String>,
+}
+
+// Sitemaps list every page in a project, so we only show where they are.
+impl std::fmt::Debug for SitemapUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.loc)
+    }
+}
+
+pub fn render_sitemap(urls: &[SitemapUrl]) -> String {
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    out.push_str("<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+    for url in urls {
+        out.push_str("  <url>\n");
+        out.push_str(&format!("    <loc>{}</loc>\n", escape(&url.loc)));
+        if let Some(lastmod) = &url.lastmod {
+            out.push_str(&format!("    <lastmod>{}</lastmod>\n", lastmod));
+        }
+        out.push_str("  </url>\n");
+    }
+    out.push_str("</urlset>\n");
+    out
+}
+
+pub fn render_robots(sitemap: Option<&str>) -> String {
+    match sitemap {
+        Some(sitemap) => format!("User-agent: *\nAllow: /\n\nSitemap: {}\n", sitemap),
+        None => "User-agent: *\nAllow: /\n".to_string(),
+    }
+}
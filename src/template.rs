@@ -0,0 +1,409 @@
+use std::collections::BTreeMap;
+
+use crate::html::escape;
+
+/// The data a template gets rendered with.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum Value {
+    Text(String),
+    /// Markup that is rendered as is, like a compiled document.
+    Html(String),
+    Number(i64),
+    Bool(bool),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+
+// Contexts can hold entire listings of documents, so we keep their debug
+// output short enough to fit in a log line.
+impl std::fmt::Debug for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Text(text) if text.chars().count() > 40 => {
+                write!(f, "{:?}..", text.chars().take(40).collect::<String>())
+            }
+            Value::Text(text) => write!(f, "{:?}", text),
+            Value::Html(html) => write!(f, "html {:?}", Value::Text(html.clone())),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::List(items) => write!(f, "[{} items]", items.len()),
+            Value::Map(fields) => f.debug_set().entries(fields.keys()).finish(),
+        }
+    }
+}
+
+impl Value {
+    pub fn map() -> Value {
+        Value::Map(BTreeMap::new())
+    }
+
+    pub fn html<S: Into<String>>(html: S) -> Value {
+        Value::Html(html.into())
+    }
+
+    pub fn with<V: Into<Value>>(self, key: &str, value: V) -> Value {
+        match self {
+            Value::Map(mut fields) => {
+                fields.insert(key.to_string(), value.into());
+                Value::Map(fields)
+            }
+            other => other,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(fields) => fields.get(key),
+            _ => None,
+        }
+    }
+
+    /// The value as JSON, for outputs meant to be read by other programs.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::Text(text) | Value::Html(text) => text.as_str().into(),
+            Value::Number(n) => (*n).into(),
+            Value::Bool(b) => (*b).into(),
+            Value::List(items) => items.iter().map(Value::to_json).collect(),
+            Value::Map(fields) => fields
+                .iter()
+                .map(|(key, value)| (key.clone(), value.to_json()))
+                .collect::<serde_json::Map<String, serde_json::Value>>()
+                .into(),
+        }
+    }
+
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Text(text) | Value::Html(text) => !text.is_empty(),
+            Value::Number(n) => *n != 0,
+            Value::Bool(b) => *b,
+            Value::List(items) => !items.is_empty(),
+            Value::Map(fields) => !fields.is_empty(),
+        }
+    }
+
+    /// The value as it goes in a page: text gets escaped, and markup is
+    /// left alone.
+    fn to_html(&self, raw: bool) -> String {
+        match self {
+            Value::Text(text) if raw => text.clone(),
+            Value::Text(text) => escape(text),
+            Value::Html(html) => html.clone(),
+            Value::Number(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::List(_) | Value::Map(_) => "".to_string(),
+        }
+    }
+}
+
+impl From<String> for Value {
+    fn from(text: String) -> Value {
+        Value::Text(text)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(text: &str) -> Value {
+        Value::Text(text.to_string())
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(n: usize) -> Value {
+        Value::Number(n as i64)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Value {
+        value.map_or(Value::Text("".to_string()), Into::into)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(items: Vec<T>) -> Value {
+        Value::List(items.into_iter().map(Into::into).collect())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateError(String);
+
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+#[derive(Debug, PartialEq)]
+enum Node {
+    Text(String),
+    Var {
+        path: String,
+        raw: bool,
+    },
+    For {
+        var: String,
+        list: String,
+        body: Vec<Node>,
+    },
+    If {
+        cond: String,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+}
+
+enum Tag<'a> {
+    Text(&'a str),
+    Code(&'a str),
+}
+
+/// Splits a template into text and the code within `{| |}`. A `{|` that is
+/// never closed, or that is followed by another `{|` before it is, is left
+/// as text, so pages that happen to contain one still render.
+fn tokenize(template: &str) -> Vec<Tag<'_>> {
+    let mut tags = vec![];
+    let mut rest = template;
+    let mut text = 0;
+    while let Some(start) = rest[text..].find("{|").map(|start| text + start) {
+        let code = &rest[start + 2..];
+        let end = match code.find("|}") {
+            Some(end) if !code[..end].contains("{|") => end,
+            _ => {
+                text = start + 2;
+                continue;
+            }
+        };
+        tags.push(Tag::Text(&rest[..start]));
+        tags.push(Tag::Code(code[..end].trim()));
+        rest = &code[end + 2..];
+        text = 0;
+    }
+    tags.push(Tag::Text(rest));
+    tags
+}
+
+/// Parses nodes until one of the `terminators` tags is found, and returns it
+/// along with the nodes.
+fn parse_nodes<'a, I>(
+    tags: &mut I,
+    terminators: &[&str],
+) -> Result<(Vec<Node>, Option<&'a str>), TemplateError>
+where
+    I: Iterator<Item = Tag<'a>>,
+{
+    let mut nodes = vec![];
+    while let Some(tag) = tags.next() {
+        let code = match tag {
+            Tag::Text(text) => {
+                nodes.push(Node::Text(text.to_string()));
+                continue;
+            }
+            Tag::Code(code) => code,
+        };
+
+        if terminators.contains(&code) {
+            return Ok((nodes, Some(code)));
+        }
+
+        let words: Vec<&str> = code.split_whitespace().collect();
+        match words.as_slice() {
+            ["for", var, "in", list] => {
+                let (body, _) = parse_block(tags, &["end"], code)?;
+                nodes.push(Node::For {
+                    var: var.to_string(),
+                    list: list.to_string(),
+                    body,
+                });
+            }
+            ["if", cond] => {
+                let (then, terminator) = parse_block(tags, &["else", "end"], code)?;
+                let otherwise = if terminator == "else" {
+                    parse_block(tags, &["end"], code)?.0
+                } else {
+                    vec![]
+                };
+                nodes.push(Node::If {
+                    cond: cond.to_string(),
+                    then,
+                    otherwise,
+                });
+            }
+            ["raw", var] => nodes.push(Node::Var {
+                path: var.to_string(),
+                raw: true,
+            }),
+            [var] if !["end", "else"].contains(var) => nodes.push(Node::Var {
+                path: var.to_string(),
+                raw: false,
+            }),
+            _ => return Err(TemplateError(format!("Unexpected `{{| {} |}}`", code))),
+        }
+    }
+    Ok((nodes, None))
+}
+
+fn parse_block<'a, I>(
+    tags: &mut I,
+    terminators: &[&str],
+    opening: &str,
+) -> Result<(Vec<Node>, &'a str), TemplateError>
+where
+    I: Iterator<Item = Tag<'a>>,
+{
+    match parse_nodes(tags, terminators)? {
+        (nodes, Some(terminator)) => Ok((nodes, terminator)),
+        (_, None) => Err(TemplateError(format!(
+            "Missing `{{| end |}}` for `{{| {} |}}`",
+            opening
+        ))),
+    }
+}
+
+fn lookup<'a>(scopes: &[(&str, &'a Value)], context: &'a Value, path: &str) -> Option<&'a Value> {
+    let mut parts = path.split('.');
+    let first = parts.next()?;
+    let mut value = scopes
+        .iter()
+        .rev()
+        .find(|(name, _)| *name == first)
+        .map(|(_, value)| *value)
+        .or_else(|| context.get(first))?;
+    for part in parts {
+        value = value.get(part)?;
+    }
+    Some(value)
+}
+
+fn render_nodes<'a>(
+    nodes: &'a [Node],
+    context: &'a Value,
+    scopes: &mut Vec<(&'a str, &'a Value)>,
+    out: &mut String,
+) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Var { path, raw } => {
+                if let Some(value) = lookup(scopes, context, path) {
+                    out.push_str(&value.to_html(*raw))
+                }
+            }
+            Node::For { var, list, body } => {
+                if let Some(Value::List(items)) = lookup(scopes, context, list) {
+                    for item in items {
+                        scopes.push((var, item));
+                        render_nodes(body, context, scopes, out);
+                        scopes.pop();
+                    }
+                }
+            }
+            Node::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let holds = lookup(scopes, context, cond).is_some_and(Value::is_truthy);
+                let branch = if holds { then } else { otherwise };
+                render_nodes(branch, context, scopes, out);
+            }
+        }
+    }
+}
+
+/// Renders a template, replacing every `{| name |}` with its value in the
+/// context. Fields of nested values can be reached with `{| a.b |}`, and
+/// templates can use `{| for x in xs |}`, `{| if x |}` and `{| else |}`
+/// blocks, closed by `{| end |}`. Missing values render as nothing.
+///
+/// Text is escaped, unless it's written as `{| raw name |}`. Values that
+/// are already markup, like a compiled document, are never escaped.
+pub fn render(template: &str, context: &Value) -> Result<String, TemplateError> {
+    let mut tags = tokenize(template).into_iter();
+    let (nodes, terminator) = parse_nodes(&mut tags, &[])?;
+    if let Some(terminator) = terminator {
+        return Err(TemplateError(format!(
+            "Unexpected `{{| {} |}}`",
+            terminator
+        )));
+    }
+    let mut out = String::new();
+    render_nodes(&nodes, context, &mut vec![], &mut out);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_vars() {
+        let context = Value::map()
+            .with("title", "Hello")
+            .with("page", Value::map().with("number", 2));
+        assert_eq!(
+            render(
+                "<h1>{| title |}</h1> {|page.number|}{| missing |}",
+                &context
+            ),
+            Ok("<h1>Hello</h1> 2".to_string())
+        );
+    }
+
+    #[test]
+    fn test_render_blocks() {
+        let entries = vec![
+            Value::map().with("title", "One").with("draft", true),
+            Value::map().with("title", "Two").with("draft", false),
+        ];
+        let context = Value::map().with("entries", entries);
+        assert_eq!(
+            render(
+                "{| for e in entries |}[{| e.title |}{| if e.draft |}*{| else |}!{| end |}]{| end |}",
+                &context
+            ),
+            Ok("[One*][Two!]".to_string())
+        );
+    }
+
+    #[test]
+    fn test_render_escapes_text() {
+        let context = Value::map()
+            .with("title", "A < B")
+            .with("document", Value::html("<p>A &lt; B</p>"));
+        assert_eq!(
+            render(
+                "<title>{| title |}</title>{| raw title |}{| document |}",
+                &context
+            ),
+            Ok("<title>A &lt; B</title>A < B<p>A &lt; B</p>".to_string())
+        );
+    }
+
+    #[test]
+    fn test_render_stray_openings() {
+        let context = Value::map().with("title", "Hi");
+        assert_eq!(
+            render("a {| b {| title |} c {|", &context),
+            Ok("a {| b Hi c {|".to_string())
+        );
+    }
+
+    #[test]
+    fn test_render_errors() {
+        let context = Value::map();
+        assert!(render("{| for e in entries |}", &context).is_err());
+        assert!(render("{| end |}", &context).is_err());
+        assert!(render("{| if a b |}{| end |}", &context).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use nipper::Document as Html;
+
+use crate::html::escape;
+use crate::model::Toc;
+use crate::template::Value;
+
+/// Where in a Markdown document its table of contents goes.
+pub const MARKER: &str = "[[toc]]";
+
+#[derive(Debug, Clone, PartialEq)]
+struct Heading {
+    level: usize,
+    id: String,
+    title: String,
+}
+
+#[derive(Debug, PartialEq)]
+struct Entry {
+    heading: Heading,
+    children: Vec<Entry>,
+}
+
+/// The headings of a compiled document, with the ids they can be linked to
+/// with.
+fn headings(html: &str) -> Vec<Heading> {
+    let html = Html::from(html);
+    html.select("h1, h2, h3, h4, h5, h6")
+        .iter()
+        .map(|heading| {
+            let level = heading
+                .nodes()
+                .first()
+                .and_then(|node| node.node_name())
+                .and_then(|name| name[1..].parse().ok())
+                .unwrap_or(1);
+            let id = heading
+                .attr("id")
+                .or_else(|| heading.select("a.anchor").attr("id"))
+                .map(|id| id.to_string())
+                .unwrap_or_default();
+            Heading {
+                level,
+                id,
+                title: heading.text().trim().to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Nests every heading under the closest heading above it with a lower level.
+fn nest(headings: &[Heading]) -> Vec<Entry> {
+    let mut entries = vec![];
+    let mut i = 0;
+    while i < headings.len() {
+        let end = headings[i + 1..]
+            .iter()
+            .position(|heading| heading.level <= headings[i].level)
+            .map_or(headings.len(), |end| i + 1 + end);
+        entries.push(Entry {
+            heading: headings[i].clone(),
+            children: nest(&headings[i + 1..end]),
+        });
+        i = end;
+    }
+    entries
+}
+
+fn entries(html: &str, toc: &Toc) -> Vec<Entry> {
+    let (min, max) = (toc.clone().min_depth(), toc.clone().max_depth());
+    let headings: Vec<Heading> = headings(html)
+        .into_iter()
+        .filter(|heading| (min..=max).contains(&heading.level))
+        .collect();
+    nest(&headings)
+}
+
+fn entry_to_value(entry: &Entry) -> Value {
+    let url = if entry.heading.id.is_empty() {
+        "".to_string()
+    } else {
+        format!("#{}", entry.heading.id)
+    };
+    Value::map()
+        .with("title", entry.heading.title.as_str())
+        .with("id", entry.heading.id.as_str())
+        .with("url", url)
+        .with("level", entry.heading.level)
+        .with(
+            "children",
+            entry
+                .children
+                .iter()
+                .map(entry_to_value)
+                .collect::<Vec<Value>>(),
+        )
+}
+
+fn entries_to_html(entries: &[Entry], out: &mut String) {
+    out.push_str("<ul>");
+    for entry in entries {
+        let title = escape(&entry.heading.title);
+        if entry.heading.id.is_empty() {
+            out.push_str(&format!("<li>{}", title));
+        } else {
+            out.push_str(&format!(
+                "<li><a href=\"#{}\">{}</a>",
+                escape(&entry.heading.id),
+                title
+            ));
+        }
+        if !entry.children.is_empty() {
+            entries_to_html(&entry.children, out);
+        }
+        out.push_str("</li>");
+    }
+    out.push_str("</ul>");
+}
+
+/// The table of contents of a compiled document, for templates to render.
+pub fn to_value(html: &str, toc: &Toc) -> Value {
+    entries(html, toc)
+        .iter()
+        .map(entry_to_value)
+        .collect::<Vec<Value>>()
+        .into()
+}
+
+/// The table of contents of a compiled document, as a nested list of links.
+pub fn to_html(html: &str, toc: &Toc) -> String {
+    let entries = entries(html, toc);
+    if entries.is_empty() {
+        return "".to_string();
+    }
+    let mut out = "<nav class=\"toc\">".to_string();
+    entries_to_html(&entries, &mut out);
+    out.push_str("</nav>");
+    out
+}
+
+/// Replaces the `[[toc]]` marker in a compiled document with its table of
+/// contents. Only a marker in a paragraph of its own counts, so documents can
+/// still show it in their text and code.
+pub fn replace_marker(html: &str, toc: &Toc) -> String {
+    let marker = format!("<p>{}</p>", MARKER);
+    if !html.contains(&marker) {
+        return html.to_string();
+    }
+    html.replace(&marker, &to_html(html, toc))
+}
+
+/// Leaves the `[[toc]]` marker out of a compiled document, for where a table
+/// of contents has no place.
+pub fn remove_marker(html: &str) -> String {
+    html.replace(&format!("<p>{}</p>", MARKER), "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replace_marker() {
+        let html = "<p>[[toc]]</p>\n<h1>Title</h1>\n\
+                    <h2><a class=\"anchor\" id=\"a\"></a>A &amp; B</h2>\n\
+                    <h3 id=\"a1\">A.1</h3>\n<h4 id=\"deep\">Deep</h4>\n<h2 id=\"c\">C</h2>\n";
+        let toc = Toc::from_sexp(&[]);
+        assert_eq!(
+            replace_marker(html, &toc).lines().next(),
+            Some(
+                "<nav class=\"toc\"><ul><li><a href=\"#a\">A &amp; B</a>\
+                 <ul><li><a href=\"#a1\">A.1</a></li></ul></li>\
+                 <li><a href=\"#c\">C</a></li></ul></nav>"
+            )
+        );
+    }
+
+    #[test]
+    fn test_replace_marker_only_on_its_own() {
+        let html = "<h2 id=\"a\">A</h2>\n<p>Write <code>[[toc]]</code> to get one.</p>\n\
+                    <pre><code>[[toc]]\n</code></pre>\n";
+        let toc = Toc::from_sexp(&[]);
+        assert_eq!(replace_marker(html, &toc), html);
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
+
+struct Event {
+    name: String,
+    category: &'static str,
+    start: Duration,
+    duration: Duration,
+    thread: usize,
+    args: serde_json::Value,
+}
+
+#[derive(Default)]
+struct Events {
+    events: Vec<Event>,
+    threads: HashMap<ThreadId, usize>,
+}
+
+/// Where a build spends its time, as events in the Chrome trace format that
+/// Perfetto and `chrome://tracing` can open. Events can be recorded from any
+/// thread, and each thread gets a lane of its own.
+pub struct Trace {
+    start: Instant,
+    events: Mutex<Events>,
+}
+
+impl Default for Trace {
+    fn default() -> Trace {
+        Trace::new()
+    }
+}
+
+impl Trace {
+    pub fn new() -> Trace {
+        Trace {
+            start: Instant::now(),
+            events: Mutex::new(Events::default()),
+        }
+    }
+
+    /// Records an event for something that started at `start` and just
+    /// finished.
+    pub fn add(&self, name: &str, category: &'static str, start: Instant, args: serde_json::Value) {
+        let duration = start.elapsed();
+        let mut events = self.events.lock().unwrap();
+        let next_thread = events.threads.len() + 1;
+        let thread = *events
+            .threads
+            .entry(std::thread::current().id())
+            .or_insert(next_thread);
+        events.events.push(Event {
+            name: name.to_string(),
+            category,
+            start: start.saturating_duration_since(self.start),
+            duration,
+            thread,
+            args,
+        });
+    }
+
+    /// Runs `f`, recording how long it took.
+    pub fn record<T, F>(&self, name: &str, category: &'static str, f: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        let start = Instant::now();
+        let result = f();
+        self.add(name, category, start, serde_json::json!({}));
+        result
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let events = self.events.lock().unwrap();
+        let pid = std::process::id();
+        let events: Vec<serde_json::Value> = events
+            .events
+            .iter()
+            .map(|event| {
+                serde_json::json!({
+                    "name": event.name,
+                    "cat": event.category,
+                    "ph": "X",
+                    "ts": event.start.as_micros() as u64,
+                    "dur": event.duration.as_micros() as u64,
+                    "pid": pid,
+                    "tid": event.thread,
+                    "args": event.args,
+                })
+            })
+            .collect();
+        serde_json::json!({
+            "traceEvents": events,
+            "displayTimeUnit": "ms",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace() {
+        let trace = Trace::new();
+        let answer = trace.record("plan", "plan", || 42);
+        assert_eq!(answer, 42);
+        std::thread::scope(|scope| {
+            scope.spawn(|| trace.record("compile", "compile", || ()));
+        });
+
+        let json = trace.to_json();
+        let events = json["traceEvents"].as_array().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["name"], "plan");
+        assert_eq!(events[0]["ph"], "X");
+        assert_eq!(events[0]["tid"], 1);
+        assert_eq!(events[1]["cat"], "compile");
+        assert_eq!(events[1]["tid"], 2);
+    }
+}